@@ -17,6 +17,7 @@ import {
     SystemProgram,
     SYSVAR_RENT_PUBKEY,
     Transaction,
+    BPF_LOADER_UPGRADEABLE_PROGRAM_ID,
 } from '@solana/web3.js';
 import {
     STAKING_PROGRAM_ID,
@@ -24,9 +25,24 @@ import {
     GlobalPool,
     AMMO_TOKEN_MINT,
     USER_POOL_SIZE,
+    USER_POOL_HEADER_SIZE,
+    STAKED_DATA_SIZE,
     AMMO_TOKEN_DECIMAL,
     UserPool,
     VAULT_SEED,
+    REWARD_CONFIG_SEED,
+    COLLECTION_REGISTRY_SEED,
+    PENDING_WITHDRAWAL_SEED,
+    REWARD_STREAM_SEED,
+    STAKE_RECEIPT_SEED,
+    USER_POOL_SEED,
+    LEGACY_USER_POOL_SIZE,
+    PendingWithdrawal,
+    RewardStream,
+    StakeReceipt,
+    DEFAULT_COLLECTIONS,
+    DEFAULT_REWARD_TIERS,
+    DEFAULT_WITHDRAW_DELAY,
 } from './types';
 import {
     getAssociatedTokenAccount,
@@ -34,6 +50,7 @@ import {
     getNFTTokenAccount,
     getOwnerOfNFT,
     getMetadata,
+    getMintTokenProgram,
     METAPLEX,
     isExistAccount,
 } from './utils';
@@ -52,19 +69,72 @@ const payer = anchor.AnchorProvider.local().wallet;
 program = new anchor.Program(StakingIDL as anchor.Idl, programId);
 console.log('ProgramId: ', program.programId.toBase58());
 
+// Pool the helpers below operate on
+let poolId = 0;
+
+export const setPoolId = (id: number) => {
+    poolId = id;
+}
+
+// The original pool predates pool ids and keeps the seeds of the old global authority and vaults
+const getPoolIdSeed = (id: number) => id === 0 ? Buffer.alloc(0) : new anchor.BN(id).toArrayLike(Buffer, "le", 8);
+
+export const getPoolAuthority = async (id: number = poolId) => {
+    return await PublicKey.findProgramAddress(
+        [Buffer.from(GLOBAL_AUTHORITY_SEED), getPoolIdSeed(id)],
+        STAKING_PROGRAM_ID,
+    );
+}
+
+export const getUserVaultKey = async (userAddress: PublicKey, id: number = poolId) => {
+    return await PublicKey.findProgramAddress(
+        [Buffer.from(VAULT_SEED), getPoolIdSeed(id), userAddress.toBuffer()],
+        STAKING_PROGRAM_ID,
+    );
+}
+
+export const getUserPoolKey = async (userAddress: PublicKey, id: number = poolId) => {
+    const [globalAuthority] = await getPoolAuthority(id);
+    const [userPoolKey] = await PublicKey.findProgramAddress(
+        [Buffer.from(USER_POOL_SEED), globalAuthority.toBuffer(), userAddress.toBuffer()],
+        STAKING_PROGRAM_ID,
+    );
+    return userPoolKey;
+}
+
+// User pools created by the client before they became PDAs, see migrateUserPool
+export const getLegacyUserPoolKey = async (userAddress: PublicKey, id: number = poolId) => {
+    return await anchor.web3.PublicKey.createWithSeed(
+        userAddress,
+        id === 0 ? "user-pool" : `user-pool-${id}`,
+        STAKING_PROGRAM_ID,
+    );
+}
+
+export const getStakeReceiptKey = async (mint: PublicKey) => {
+    return await PublicKey.findProgramAddress(
+        [Buffer.from(STAKE_RECEIPT_SEED), mint.toBuffer()],
+        STAKING_PROGRAM_ID,
+    );
+}
+
+export const getRewardStreamKey = async (globalAuthority: PublicKey, index: number) => {
+    return await PublicKey.findProgramAddress(
+        [Buffer.from(REWARD_STREAM_SEED), globalAuthority.toBuffer(), Buffer.from([index])],
+        STAKING_PROGRAM_ID,
+    );
+}
+
 // Update Authority Keypair
 const updateKeypair = Keypair.fromSecretKey(Uint8Array.from(JSON.parse(fs.readFileSync(__dirname + '/../tests/keys/supernova.json', 'utf-8'))), { skipValidation: true });
 console.log("Update authority pubkey= ", updateKeypair.publicKey.toBase58());
 const main = async () => {
-    const [globalAuthority, bump] = await PublicKey.findProgramAddress(
-        [Buffer.from(GLOBAL_AUTHORITY_SEED)],
-        program.programId
-    );
+    const [globalAuthority, bump] = await getPoolAuthority();
     console.log('GlobalAuthority: ', globalAuthority.toBase58());
 
     // const metadata = await getMetadata(new PublicKey("AwYBNbV3G1mdRbGenE5Xv9hoXPePJctstmHh93Hwj7KZ"));
     // console.log(metadata.toBase58());
-    await initProject();
+    await createPool(poolId);
 
     // await initUserPool();
 
@@ -86,6 +156,8 @@ const main = async () => {
     // console.log(tx.transaction.message.instructions);
     // await stakeNFT(new PublicKey('5vYFr2LvdC9HJZhw3BxFZnFbCAigxLJ8RAnEESq5Duwp'), 3);
     // await withdrawNft(new PublicKey('5vYFr2LvdC9HJZhw3BxFZnFbCAigxLJ8RAnEESq5Duwp'));
+    // await stakeNFTs([new PublicKey('5vYFr2LvdC9HJZhw3BxFZnFbCAigxLJ8RAnEESq5Duwp')], 3);
+    // await withdrawNfts([new PublicKey('5vYFr2LvdC9HJZhw3BxFZnFbCAigxLJ8RAnEESq5Duwp')]);
     // const userPool: UserPool = await getUserPoolState(payer.publicKey, program);
     // await testMetadata(new PublicKey("22HpVhS1SmUQHBCz9sPansBwyziDC77Rs1jkPHCPMsf3"));
 
@@ -102,9 +174,24 @@ export const testMetadata = async (
     console.log("MintMetadata's Creator Addresses =", creators.map((creator) => creator.address));
 }
 
-export const initProject = async (
+export const createPool = async (
+    id: number,
+    rewardMint: PublicKey = AMMO_TOKEN_MINT,
+) => {
+    const tx = await createCreatePoolTx(payer.publicKey, id, rewardMint, program);
+    const { blockhash } = await solConnection.getRecentBlockhash('confirmed');
+    tx.feePayer = payer.publicKey;
+    tx.recentBlockhash = blockhash;
+    payer.signTransaction(tx);
+    let txId = await solConnection.sendTransaction(tx, [(payer as NodeWallet).payer]);
+    await solConnection.confirmTransaction(txId, "confirmed");
+    console.log("txHash =", txId);
+}
+
+
+export const migrateGlobalPool = async (
 ) => {
-    const tx = await createInitializeTx(payer.publicKey, program);
+    const tx = await createMigrateGlobalPoolTx(payer.publicKey, program);
     const { blockhash } = await solConnection.getRecentBlockhash('confirmed');
     tx.feePayer = payer.publicKey;
     tx.recentBlockhash = blockhash;
@@ -114,6 +201,33 @@ export const initProject = async (
     console.log("txHash =", txId);
 }
 
+// Upgrade the layout of a keypair user pool, then move it to its PDA.
+// Pools with many stakes take several calls to grow the PDA before the move.
+export const migrateUserPool = async (
+) => {
+    let legacyUserPoolKey = await getLegacyUserPoolKey(payer.publicKey);
+    let legacyAccount = await solConnection.getAccountInfo(legacyUserPoolKey);
+    if (legacyAccount === null) return;
+
+    let txs = [];
+    if (legacyAccount.data.length === LEGACY_USER_POOL_SIZE) {
+        txs.push(await createMigrateUserPoolTx(payer.publicKey, program));
+    }
+
+    while (true) {
+        for (const tx of [...txs, await createMigrateUserPoolToPdaTx(payer.publicKey, program)]) {
+            const { blockhash } = await solConnection.getRecentBlockhash('confirmed');
+            tx.feePayer = payer.publicKey;
+            tx.recentBlockhash = blockhash;
+            payer.signTransaction(tx);
+            let txId = await solConnection.sendTransaction(tx, [(payer as NodeWallet).payer]);
+            await solConnection.confirmTransaction(txId, "confirmed");
+            console.log("Your transaction signature", txId);
+        }
+        txs = [];
+        if (!(await isExistAccount(legacyUserPoolKey, solConnection))) break;
+    }
+}
 
 export const initUserPool = async (
 ) => {
@@ -187,16 +301,9 @@ export const fusion = async (
     amount: number,
     newUri: string,
 ) => {
-    const [userVault, bump] = await PublicKey.findProgramAddress(
-        [Buffer.from(VAULT_SEED), payer.publicKey.toBuffer()],
-        STAKING_PROGRAM_ID,
-    );
+    const [userVault, bump] = await getUserVaultKey(payer.publicKey);
     console.log(userVault.toBase58());
-    let userPoolKey = await anchor.web3.PublicKey.createWithSeed(
-        payer.publicKey,
-        "user-pool",
-        STAKING_PROGRAM_ID,
-    );
+    let userPoolKey = await getUserPoolKey(payer.publicKey);
 
     let poolAccount = await solConnection.getAccountInfo(userPoolKey);
     if (poolAccount === null || poolAccount.data === null) {
@@ -220,11 +327,7 @@ export const stakeNFT = async (
 ) => {
     console.log(mint.toBase58(), duration);
 
-    let userPoolKey = await anchor.web3.PublicKey.createWithSeed(
-        payer.publicKey,
-        "user-pool",
-        STAKING_PROGRAM_ID,
-    );
+    let userPoolKey = await getUserPoolKey(payer.publicKey);
 
     let poolAccount = await solConnection.getAccountInfo(userPoolKey);
     if (poolAccount === null || poolAccount.data === null) {
@@ -258,6 +361,99 @@ export const withdrawNft = async (
 }
 
 
+export const stakeNFTs = async (
+    mints: PublicKey[],
+    duration: number,
+) => {
+    console.log(mints.map((mint) => mint.toBase58()), duration);
+
+    let userPoolKey = await getUserPoolKey(payer.publicKey);
+
+    let poolAccount = await solConnection.getAccountInfo(userPoolKey);
+    if (poolAccount === null || poolAccount.data === null) {
+        await initUserPool();
+    }
+
+    const tx = await createStakeNftsBatchTx(mints, payer.publicKey, program, solConnection, duration);
+    const { blockhash } = await solConnection.getRecentBlockhash('confirmed');
+    tx.feePayer = payer.publicKey;
+    tx.recentBlockhash = blockhash;
+    payer.signTransaction(tx);
+    let txId = await solConnection.sendTransaction(tx, [(payer as NodeWallet).payer]);
+    await solConnection.confirmTransaction(txId, "confirmed");
+    console.log("Your transaction signature", txId);
+}
+
+
+export const withdrawNfts = async (
+    mints: PublicKey[],
+) => {
+    console.log(mints.map((mint) => mint.toBase58()));
+
+    const tx = await createWithdrawNftsBatchTx(mints, payer.publicKey, program, solConnection);
+    const { blockhash } = await solConnection.getRecentBlockhash('confirmed');
+    tx.feePayer = payer.publicKey;
+    tx.recentBlockhash = blockhash;
+    payer.signTransaction(tx);
+    let txId = await solConnection.sendTransaction(tx, [(payer as NodeWallet).payer]);
+    await solConnection.confirmTransaction(txId, "confirmed");
+    console.log("Your transaction signature", txId);
+}
+
+
+export const emergencyWithdrawNft = async (
+    mint: PublicKey,
+) => {
+    console.log(mint.toBase58());
+
+    const tx = await createEmergencyWithdrawNftTx(mint, payer.publicKey, program, solConnection);
+    const { blockhash } = await solConnection.getRecentBlockhash('confirmed');
+    tx.feePayer = payer.publicKey;
+    tx.recentBlockhash = blockhash;
+    payer.signTransaction(tx);
+    let txId = await solConnection.sendTransaction(tx, [(payer as NodeWallet).payer]);
+    await solConnection.confirmTransaction(txId, "confirmed");
+    console.log("Your transaction signature", txId);
+}
+
+
+export const claimRewards = async (
+) => {
+    const tx = await createClaimRewardsTx(payer.publicKey, program, solConnection);
+    const { blockhash } = await solConnection.getRecentBlockhash('confirmed');
+    tx.feePayer = payer.publicKey;
+    tx.recentBlockhash = blockhash;
+    payer.signTransaction(tx);
+    let txId = await solConnection.sendTransaction(tx, [(payer as NodeWallet).payer]);
+    await solConnection.confirmTransaction(txId, "confirmed");
+    console.log("Your transaction signature", txId);
+}
+
+export const closeUserPool = async (
+) => {
+    const tx = await createCloseUserPoolTx(payer.publicKey, program, solConnection);
+    const { blockhash } = await solConnection.getRecentBlockhash('confirmed');
+    tx.feePayer = payer.publicKey;
+    tx.recentBlockhash = blockhash;
+    payer.signTransaction(tx);
+    let txId = await solConnection.sendTransaction(tx, [(payer as NodeWallet).payer]);
+    await solConnection.confirmTransaction(txId, "confirmed");
+    console.log("Your transaction signature", txId);
+}
+
+export const closeUserVault = async (
+) => {
+    const tx = await createCloseUserVaultTx(payer.publicKey, program, solConnection);
+    const { blockhash } = await solConnection.getRecentBlockhash('confirmed');
+    tx.feePayer = payer.publicKey;
+    tx.recentBlockhash = blockhash;
+    payer.signTransaction(tx);
+    let txId = await solConnection.sendTransaction(tx, [(payer as NodeWallet).payer]);
+    await solConnection.confirmTransaction(txId, "confirmed");
+    console.log("Your transaction signature", txId);
+}
+
+
 export const withdrawToken = async (
     amount: number
 ) => {
@@ -271,24 +467,79 @@ export const withdrawToken = async (
     console.log("Your transaction signature", txId);
 }
 
+export const queueWithdrawal = async (
+    amount: number
+) => {
+    const tx = await createQueueWithdrawalTx(payer.publicKey, amount, program, solConnection);
+    const { blockhash } = await solConnection.getRecentBlockhash('confirmed');
+    tx.feePayer = payer.publicKey;
+    tx.recentBlockhash = blockhash;
+    payer.signTransaction(tx);
+    let txId = await solConnection.sendTransaction(tx, [(payer as NodeWallet).payer]);
+    await solConnection.confirmTransaction(txId, "confirmed");
+    console.log("Your transaction signature", txId);
+}
+
+export const cancelWithdrawal = async (
+    id: number
+) => {
+    const tx = await createCancelWithdrawalTx(payer.publicKey, id, program, solConnection);
+    const { blockhash } = await solConnection.getRecentBlockhash('confirmed');
+    tx.feePayer = payer.publicKey;
+    tx.recentBlockhash = blockhash;
+    payer.signTransaction(tx);
+    let txId = await solConnection.sendTransaction(tx, [(payer as NodeWallet).payer]);
+    await solConnection.confirmTransaction(txId, "confirmed");
+    console.log("Your transaction signature", txId);
+}
+
+export const executeWithdrawal = async (
+    id: number
+) => {
+    const tx = await createExecuteWithdrawalTx(payer.publicKey, id, program, solConnection);
+    const { blockhash } = await solConnection.getRecentBlockhash('confirmed');
+    tx.feePayer = payer.publicKey;
+    tx.recentBlockhash = blockhash;
+    payer.signTransaction(tx);
+    let txId = await solConnection.sendTransaction(tx, [(payer as NodeWallet).payer]);
+    await solConnection.confirmTransaction(txId, "confirmed");
+    console.log("Your transaction signature", txId);
+}
+
 
-export const createInitializeTx = async (
+export const createCreatePoolTx = async (
     userAddress: PublicKey,
+    id: number,
+    rewardMint: PublicKey,
     program: anchor.Program,
 ) => {
-    const [globalAuthority, bump] = await PublicKey.findProgramAddress(
-        [Buffer.from(GLOBAL_AUTHORITY_SEED)],
+    const [globalAuthority, bump] = await getPoolAuthority(id);
+    const [rewardConfig] = await PublicKey.findProgramAddress(
+        [Buffer.from(REWARD_CONFIG_SEED), globalAuthority.toBuffer()],
+        STAKING_PROGRAM_ID,
+    );
+    const [collectionRegistry] = await PublicKey.findProgramAddress(
+        [Buffer.from(COLLECTION_REGISTRY_SEED), globalAuthority.toBuffer()],
         STAKING_PROGRAM_ID,
     );
+    const [programData] = await PublicKey.findProgramAddress(
+        [STAKING_PROGRAM_ID.toBuffer()],
+        BPF_LOADER_UPGRADEABLE_PROGRAM_ID,
+    );
 
     let tx = new Transaction();
-    console.log('==>Initializing Program');
+    console.log('==>Creating Pool', id);
 
-    tx.add(program.instruction.initialize(
-        bump, {
+    tx.add(program.instruction.createPool(
+        bump, new anchor.BN(id), new anchor.BN(DEFAULT_WITHDRAW_DELAY), DEFAULT_COLLECTIONS, DEFAULT_REWARD_TIERS, {
         accounts: {
             admin: userAddress,
             globalAuthority,
+            rewardConfig,
+            collectionRegistry,
+            rewardMint,
+            program: STAKING_PROGRAM_ID,
+            programData,
             systemProgram: SystemProgram.programId,
             rent: SYSVAR_RENT_PUBKEY,
         },
@@ -299,49 +550,137 @@ export const createInitializeTx = async (
     return tx;
 }
 
-export const createInitUserPoolTx = async (
+export const createMigrateGlobalPoolTx = async (
     userAddress: PublicKey,
     program: anchor.Program,
-    connection: Connection,
 ) => {
-    let userPoolKey = await anchor.web3.PublicKey.createWithSeed(
-        userAddress,
-        "user-pool",
+    // Legacy accounts all belong to the original pool
+    const [globalAuthority] = await getPoolAuthority(0);
+    const [rewardConfig] = await PublicKey.findProgramAddress(
+        [Buffer.from(REWARD_CONFIG_SEED), globalAuthority.toBuffer()],
         STAKING_PROGRAM_ID,
     );
-
-    const [userVault, bump] = await PublicKey.findProgramAddress(
-        [Buffer.from(VAULT_SEED), userAddress.toBuffer()],
+    const [collectionRegistry] = await PublicKey.findProgramAddress(
+        [Buffer.from(COLLECTION_REGISTRY_SEED), globalAuthority.toBuffer()],
         STAKING_PROGRAM_ID,
     );
-    console.log(USER_POOL_SIZE);
-    let ix = SystemProgram.createAccountWithSeed({
-        fromPubkey: userAddress,
-        basePubkey: userAddress,
-        seed: "user-pool",
-        newAccountPubkey: userPoolKey,
-        lamports: await connection.getMinimumBalanceForRentExemption(USER_POOL_SIZE),
-        space: USER_POOL_SIZE,
-        programId: STAKING_PROGRAM_ID,
-    });
+
+    let tx = new Transaction();
+    console.log('==>Migrating Global Pool');
+
+    tx.add(program.instruction.migrateGlobalPool(
+        DEFAULT_COLLECTIONS, DEFAULT_REWARD_TIERS, {
+        accounts: {
+            admin: userAddress,
+            globalAuthority,
+            rewardConfig,
+            collectionRegistry,
+            rewardMint: AMMO_TOKEN_MINT,
+            systemProgram: SystemProgram.programId,
+            rent: SYSVAR_RENT_PUBKEY,
+        },
+        instructions: [],
+        signers: [],
+    }));
+
+    return tx;
+}
+
+export const createMigrateUserPoolTx = async (
+    userAddress: PublicKey,
+    program: anchor.Program,
+) => {
+    // Unversioned accounts all belong to the original pool
+    let userPoolKey = await getLegacyUserPoolKey(userAddress);
+    const [globalAuthority] = await getPoolAuthority();
+
+    let tx = new Transaction();
+    console.log('==>Migrating User Pool', userPoolKey.toBase58());
+
+    tx.add(program.instruction.migrateUserPool(
+        {
+        accounts: {
+            owner: userAddress,
+            userPool: userPoolKey,
+            globalAuthority,
+            systemProgram: SystemProgram.programId,
+        },
+        instructions: [],
+        signers: [],
+    }));
+
+    return tx;
+}
+
+export const createMigrateUserPoolToPdaTx = async (
+    userAddress: PublicKey,
+    program: anchor.Program,
+) => {
+    let legacyUserPool = await getLegacyUserPoolKey(userAddress);
+    let userPoolKey = await getUserPoolKey(userAddress);
+    const [globalAuthority] = await getPoolAuthority();
+
+    let tx = new Transaction();
+    console.log('==>Moving User Pool', legacyUserPool.toBase58(), 'to', userPoolKey.toBase58());
+
+    tx.add(program.instruction.migrateUserPoolToPda(
+        {
+        accounts: {
+            owner: userAddress,
+            legacyUserPool,
+            userPool: userPoolKey,
+            globalAuthority,
+            systemProgram: SystemProgram.programId,
+        },
+        instructions: [],
+        signers: [],
+    }));
+
+    return tx;
+}
+
+export const createInitUserPoolTx = async (
+    userAddress: PublicKey,
+    program: anchor.Program,
+    connection: Connection,
+) => {
+    let userPoolKey = await getUserPoolKey(userAddress);
+
+    const [globalAuthority] = await getPoolAuthority();
+    const [userVault] = await getUserVaultKey(userAddress);
 
     let tx = new Transaction();
     console.log('==>initializing user PDA', userPoolKey.toBase58());
-    tx.add(ix);
     tx.add(program.instruction.initializeUserPool(
         {
             accounts: {
-                userPool: userPoolKey,
-                userVault,
+                globalAuthority,
                 owner: userAddress,
+                userPool: userPoolKey,
                 systemProgram: SystemProgram.programId,
-                rent: SYSVAR_RENT_PUBKEY,
             },
             instructions: [],
             signers: []
         }
     ));
 
+    // Each pool has its own vault, a user pool recreated after a close may still have it
+    if (!(await isExistAccount(userVault, connection))) {
+        tx.add(program.instruction.initializeUserVault(
+            {
+                accounts: {
+                    globalAuthority,
+                    userVault,
+                    owner: userAddress,
+                    systemProgram: SystemProgram.programId,
+                    rent: SYSVAR_RENT_PUBKEY,
+                },
+                instructions: [],
+                signers: []
+            }
+        ));
+    }
+
     return tx;
 }
 
@@ -352,17 +691,18 @@ export const createDepositToAccountTx = async (
     program: anchor.Program,
     connection: Connection,
 ) => {
+    const rewardMint = await getRewardMint(program);
+    const rewardTokenProgram = await getMintTokenProgram(rewardMint, connection);
+    const [globalAuthority] = await getPoolAuthority();
 
-    const [userVault, bump] = await PublicKey.findProgramAddress(
-        [Buffer.from(VAULT_SEED), userAddress.toBuffer()],
-        STAKING_PROGRAM_ID,
-    );
-    let userTokenAccount = await getAssociatedTokenAccount(userAddress, AMMO_TOKEN_MINT);
+    const [userVault, bump] = await getUserVaultKey(userAddress);
+    let userTokenAccount = await getAssociatedTokenAccount(userAddress, rewardMint, rewardTokenProgram);
     let { instructions, destinationAccounts } = await getATokenAccountsNeedCreate(
         connection,
         userAddress,
         userVault,
-        [AMMO_TOKEN_MINT]
+        [rewardMint],
+        rewardTokenProgram,
     );
 
     let tx = new Transaction();
@@ -372,11 +712,12 @@ export const createDepositToAccountTx = async (
         new anchor.BN(amount * AMMO_TOKEN_DECIMAL), {
             accounts: {
                 owner: userAddress,
+                globalAuthority,
                 userVault,
                 userTokenAccount,
                 destTokenAccount: destinationAccounts[0],
-                tokenProgram: TOKEN_PROGRAM_ID,
-
+                rewardMint,
+                tokenProgram: rewardTokenProgram,
             },
             instructions: [],
             signers: []
@@ -393,17 +734,18 @@ export const createWithdrawFromAccountTx = async (
     program: anchor.Program,
     connection: Connection,
 ) => {
+    const rewardMint = await getRewardMint(program);
+    const rewardTokenProgram = await getMintTokenProgram(rewardMint, connection);
+    const [globalAuthority] = await getPoolAuthority();
 
-    const [userVault, bump] = await PublicKey.findProgramAddress(
-        [Buffer.from(VAULT_SEED), userAddress.toBuffer()],
-        STAKING_PROGRAM_ID,
-    );
-    let userTokenAccount = await getAssociatedTokenAccount(userVault, AMMO_TOKEN_MINT);
+    const [userVault, bump] = await getUserVaultKey(userAddress);
+    let userTokenAccount = await getAssociatedTokenAccount(userVault, rewardMint, rewardTokenProgram);
     let { instructions, destinationAccounts } = await getATokenAccountsNeedCreate(
         connection,
         userAddress,
         userAddress,
-        [AMMO_TOKEN_MINT]
+        [rewardMint],
+        rewardTokenProgram,
     );
 
     let tx = new Transaction();
@@ -413,11 +755,12 @@ export const createWithdrawFromAccountTx = async (
         bump, new anchor.BN(amount * AMMO_TOKEN_DECIMAL), {
             accounts: {
                 owner: userAddress,
+                globalAuthority,
                 userVault,
                 userTokenAccount,
                 destTokenAccount: destinationAccounts[0],
-                tokenProgram: TOKEN_PROGRAM_ID,
-
+                rewardMint,
+                tokenProgram: rewardTokenProgram,
             },
             instructions: [],
             signers: []
@@ -434,17 +777,13 @@ export const createDepositToVaultTx = async (
     program: anchor.Program,
     connection: Connection,
 ) => {
-    const [globalAuthority, globalBump] = await PublicKey.findProgramAddress(
-        [Buffer.from(GLOBAL_AUTHORITY_SEED)],
-        STAKING_PROGRAM_ID,
-    );
+    const rewardMint = await getRewardMint(program);
+    const rewardTokenProgram = await getMintTokenProgram(rewardMint, connection);
+    const [globalAuthority, globalBump] = await getPoolAuthority();
 
-    const [userVault, bump] = await PublicKey.findProgramAddress(
-        [Buffer.from(VAULT_SEED), userAddress.toBuffer()],
-        STAKING_PROGRAM_ID,
-    );
-    let rewardVault = await getAssociatedTokenAccount(globalAuthority, AMMO_TOKEN_MINT);
-    let userTokenAccount = await getAssociatedTokenAccount(userVault, AMMO_TOKEN_MINT);
+    const [userVault, bump] = await getUserVaultKey(userAddress);
+    let rewardVault = await getAssociatedTokenAccount(globalAuthority, rewardMint, rewardTokenProgram);
+    let userTokenAccount = await getAssociatedTokenAccount(userVault, rewardMint, rewardTokenProgram);
 
     let tx = new Transaction();
     console.log('==>Depositing to Vault...', rewardVault.toBase58());
@@ -456,8 +795,8 @@ export const createDepositToVaultTx = async (
                 userVault,
                 rewardVault,
                 userTokenAccount,
-                tokenProgram: TOKEN_PROGRAM_ID,
-
+                rewardMint,
+                tokenProgram: rewardTokenProgram,
             },
             instructions: [],
             signers: []
@@ -474,21 +813,18 @@ export const createWithdrawFromVaultTx = async (
     program: anchor.Program,
     connection: Connection,
 ) => {
-    const [globalAuthority, globalBump] = await PublicKey.findProgramAddress(
-        [Buffer.from(GLOBAL_AUTHORITY_SEED)],
-        STAKING_PROGRAM_ID,
-    );
+    const rewardMint = await getRewardMint(program);
+    const rewardTokenProgram = await getMintTokenProgram(rewardMint, connection);
+    const [globalAuthority, globalBump] = await getPoolAuthority();
 
-    const [userVault, bump] = await PublicKey.findProgramAddress(
-        [Buffer.from(VAULT_SEED), userAddress.toBuffer()],
-        STAKING_PROGRAM_ID,
-    );
-    let rewardVault = await getAssociatedTokenAccount(globalAuthority, AMMO_TOKEN_MINT);
+    const [userVault, bump] = await getUserVaultKey(userAddress);
+    let rewardVault = await getAssociatedTokenAccount(globalAuthority, rewardMint, rewardTokenProgram);
     let { instructions, destinationAccounts } = await getATokenAccountsNeedCreate(
         connection,
         userAddress,
         userVault,
-        [AMMO_TOKEN_MINT]
+        [rewardMint],
+        rewardTokenProgram,
     );
 
     let tx = new Transaction();
@@ -503,7 +839,8 @@ export const createWithdrawFromVaultTx = async (
                 userVault,
                 rewardVault,
                 destTokenAccount: destinationAccounts[0],
-                tokenProgram: TOKEN_PROGRAM_ID,
+                rewardMint,
+                tokenProgram: rewardTokenProgram,
             },
             instructions: [],
             signers: []
@@ -522,19 +859,19 @@ export const createFusionTx = async (
     program: anchor.Program,
     connection: Connection,
 ) => {
-    const [globalAuthority, globalBump] = await PublicKey.findProgramAddress(
-        [Buffer.from(GLOBAL_AUTHORITY_SEED)],
-        STAKING_PROGRAM_ID,
-    );
+    const rewardMint = await getRewardMint(program);
+    const rewardTokenProgram = await getMintTokenProgram(rewardMint, connection);
+    const [globalAuthority, globalBump] = await getPoolAuthority();
 
-    const [userVault, bump] = await PublicKey.findProgramAddress(
-        [Buffer.from(VAULT_SEED), userAddress.toBuffer()],
-        STAKING_PROGRAM_ID,
-    );
-    let rewardVault = await getAssociatedTokenAccount(globalAuthority, AMMO_TOKEN_MINT);
-    let userTokenAccount = await getAssociatedTokenAccount(userVault, AMMO_TOKEN_MINT);
+    const [userVault, bump] = await getUserVaultKey(userAddress);
+    let rewardVault = await getAssociatedTokenAccount(globalAuthority, rewardMint, rewardTokenProgram);
+    let userTokenAccount = await getAssociatedTokenAccount(userVault, rewardMint, rewardTokenProgram);
 
     const metadata = await getMetadata(nftMint);
+    const [collectionRegistry] = await PublicKey.findProgramAddress(
+        [Buffer.from(COLLECTION_REGISTRY_SEED), globalAuthority.toBuffer()],
+        STAKING_PROGRAM_ID,
+    );
 
     let tx = new Transaction();
     console.log('==>Fusioning...', nftMint.toBase58());
@@ -543,6 +880,7 @@ export const createFusionTx = async (
             accounts: {
                 owner: userAddress,
                 globalAuthority,
+                collectionRegistry,
                 updateAuthority: updateKeypair.publicKey,
                 userVault,
                 rewardVault,
@@ -550,7 +888,8 @@ export const createFusionTx = async (
                 nftMint,
                 mintMetadata: metadata,
                 tokenMetadataProgram: METAPLEX,
-                tokenProgram: TOKEN_PROGRAM_ID,
+                rewardMint,
+                tokenProgram: rewardTokenProgram,
             },
             instructions: [],
             signers: [updateKeypair]
@@ -567,28 +906,13 @@ export const createStakeNftTx = async (
     connection: Connection,
     duration: number,
 ) => {
-    const [globalAuthority, bump] = await PublicKey.findProgramAddress(
-        [Buffer.from(GLOBAL_AUTHORITY_SEED)],
-        STAKING_PROGRAM_ID,
-    );
+    const rewardMint = await getRewardMint(program);
+    const rewardTokenProgram = await getMintTokenProgram(rewardMint, connection);
+    const [globalAuthority, bump] = await getPoolAuthority();
 
-    let userPoolKey = await anchor.web3.PublicKey.createWithSeed(
-        userAddress,
-        "user-pool",
-        STAKING_PROGRAM_ID,
-    );
+    let userPoolKey = await getUserPoolKey(userAddress);
 
-    let userTokenAccount = await getAssociatedTokenAccount(userAddress, mint);
-    if (!await isExistAccount(userTokenAccount, connection)) {
-        let accountOfNFT = await getNFTTokenAccount(mint, connection);
-        if (userTokenAccount.toBase58() != accountOfNFT.toBase58()) {
-            let nftOwner = await getOwnerOfNFT(mint, connection);
-            if (nftOwner.toBase58() == userAddress.toBase58()) userTokenAccount = accountOfNFT;
-            else if (nftOwner.toBase58() !== globalAuthority.toBase58()) {
-                throw 'Error: Nft is not owned by user';
-            }
-        }
-    }
+    let userTokenAccount = await getUserNftTokenAccount(mint, userAddress, globalAuthority, connection);
     console.log("NFT = ", mint.toBase58(), userTokenAccount.toBase58());
 
     let { instructions, destinationAccounts } = await getATokenAccountsNeedCreate(
@@ -604,6 +928,18 @@ export const createStakeNftTx = async (
 
     console.log("Metadata=", metadata.toBase58());
 
+    const [rewardConfig] = await PublicKey.findProgramAddress(
+        [Buffer.from(REWARD_CONFIG_SEED), globalAuthority.toBuffer()],
+        STAKING_PROGRAM_ID,
+    );
+    const [collectionRegistry] = await PublicKey.findProgramAddress(
+        [Buffer.from(COLLECTION_REGISTRY_SEED), globalAuthority.toBuffer()],
+        STAKING_PROGRAM_ID,
+    );
+    let rewardVault = await getAssociatedTokenAccount(globalAuthority, rewardMint, rewardTokenProgram);
+    const streams = await getRewardStreams(program);
+    const [stakeReceipt] = await getStakeReceiptKey(mint);
+
     let tx = new Transaction();
 
     if (instructions.length > 0) instructions.map((ix) => tx.add(ix));
@@ -614,6 +950,9 @@ export const createStakeNftTx = async (
         accounts: {
             owner: userAddress,
             globalAuthority,
+            rewardConfig,
+            collectionRegistry,
+            rewardVault,
             userPool: userPoolKey,
             userNftTokenAccount: userTokenAccount,
             destNftTokenAccount: destinationAccounts[0],
@@ -621,7 +960,102 @@ export const createStakeNftTx = async (
             mintMetadata: metadata,
             tokenProgram: TOKEN_PROGRAM_ID,
             tokenMetadataProgram: METAPLEX,
+            stakeReceipt,
+            systemProgram: SystemProgram.programId,
+        },
+        remainingAccounts: streams.map(({ address }) => ({ pubkey: address, isSigner: false, isWritable: false })),
+        instructions: [],
+        signers: [],
+    }));
+
+    return tx;
+}
+
+// The user's token account holding an NFT, which isn't always its associated token account
+const getUserNftTokenAccount = async (
+    mint: PublicKey,
+    userAddress: PublicKey,
+    globalAuthority: PublicKey,
+    connection: Connection,
+) => {
+    let userTokenAccount = await getAssociatedTokenAccount(userAddress, mint);
+    if (!await isExistAccount(userTokenAccount, connection)) {
+        let accountOfNFT = await getNFTTokenAccount(mint, connection);
+        if (userTokenAccount.toBase58() != accountOfNFT.toBase58()) {
+            let nftOwner = await getOwnerOfNFT(mint, connection);
+            if (nftOwner.toBase58() == userAddress.toBase58()) userTokenAccount = accountOfNFT;
+            else if (nftOwner.toBase58() !== globalAuthority.toBase58()) {
+                throw 'Error: Nft is not owned by user';
+            }
+        }
+    }
+    return userTokenAccount;
+}
+
+export const createStakeNftsBatchTx = async (
+    mints: PublicKey[],
+    userAddress: PublicKey,
+    program: anchor.Program,
+    connection: Connection,
+    duration: number,
+) => {
+    const rewardMint = await getRewardMint(program);
+    const rewardTokenProgram = await getMintTokenProgram(rewardMint, connection);
+    const [globalAuthority] = await getPoolAuthority();
+
+    let userPoolKey = await getUserPoolKey(userAddress);
+
+    let { instructions, destinationAccounts } = await getATokenAccountsNeedCreate(
+        connection,
+        userAddress,
+        globalAuthority,
+        mints
+    );
+
+    const [rewardConfig] = await PublicKey.findProgramAddress(
+        [Buffer.from(REWARD_CONFIG_SEED), globalAuthority.toBuffer()],
+        STAKING_PROGRAM_ID,
+    );
+    const [collectionRegistry] = await PublicKey.findProgramAddress(
+        [Buffer.from(COLLECTION_REGISTRY_SEED), globalAuthority.toBuffer()],
+        STAKING_PROGRAM_ID,
+    );
+    let rewardVault = await getAssociatedTokenAccount(globalAuthority, rewardMint, rewardTokenProgram);
+    const streams = await getRewardStreams(program);
+
+    // The pool's streams first, then [mint, metadata, user token account, pool token account, receipt] per NFT
+    let remainingAccounts = streams.map(({ address }) => ({ pubkey: address, isSigner: false, isWritable: false }));
+    for (const [index, mint] of mints.entries()) {
+        let userTokenAccount = await getUserNftTokenAccount(mint, userAddress, globalAuthority, connection);
+        console.log("NFT = ", mint.toBase58(), userTokenAccount.toBase58());
+        const [stakeReceipt] = await getStakeReceiptKey(mint);
+        remainingAccounts.push(
+            { pubkey: mint, isSigner: false, isWritable: false },
+            { pubkey: await getMetadata(mint), isSigner: false, isWritable: false },
+            { pubkey: userTokenAccount, isSigner: false, isWritable: true },
+            { pubkey: destinationAccounts[index], isSigner: false, isWritable: true },
+            { pubkey: stakeReceipt, isSigner: false, isWritable: true },
+        );
+    }
+
+    let tx = new Transaction();
+
+    if (instructions.length > 0) instructions.map((ix) => tx.add(ix));
+    console.log('==>Staking ...', mints.length, duration);
+
+    tx.add(program.instruction.stakeNftsBatch(
+        new anchor.BN(duration), {
+        accounts: {
+            owner: userAddress,
+            userPool: userPoolKey,
+            globalAuthority,
+            rewardConfig,
+            collectionRegistry,
+            rewardVault,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            systemProgram: SystemProgram.programId,
         },
+        remainingAccounts,
         instructions: [],
         signers: [],
     }));
@@ -635,36 +1069,40 @@ export const createWithdrawNftTx = async (
     program: anchor.Program,
     connection: Connection,
 ) => {
+    const rewardMint = await getRewardMint(program);
+    const rewardTokenProgram = await getMintTokenProgram(rewardMint, connection);
     let ret = await getATokenAccountsNeedCreate(
         connection,
         userAddress,
         userAddress,
-        [mint, AMMO_TOKEN_MINT]
+        [mint]
     );
 
-    const [userVault, userbump] = await PublicKey.findProgramAddress(
-        [Buffer.from(VAULT_SEED), payer.publicKey.toBuffer()],
-        STAKING_PROGRAM_ID,
+    const [userVault, userbump] = await getUserVaultKey(payer.publicKey);
+    let rewardRet = await getATokenAccountsNeedCreate(
+        connection,
+        userAddress,
+        userVault,
+        [rewardMint],
+        rewardTokenProgram,
     );
     let userTokenAccount = ret.destinationAccounts[0];
     console.log("User NFT = ", mint.toBase58(), userTokenAccount.toBase58());
 
-    const [globalAuthority, bump] = await PublicKey.findProgramAddress(
-        [Buffer.from(GLOBAL_AUTHORITY_SEED)],
-        STAKING_PROGRAM_ID
-    );
-    let rewardVault = await getAssociatedTokenAccount(globalAuthority, AMMO_TOKEN_MINT);
+    const [globalAuthority, bump] = await getPoolAuthority();
+    let rewardVault = await getAssociatedTokenAccount(globalAuthority, rewardMint, rewardTokenProgram);
     let destNftTokenAccount = await getAssociatedTokenAccount(globalAuthority, mint);
 
-    let userPoolKey = await anchor.web3.PublicKey.createWithSeed(
-        userAddress,
-        "user-pool",
-        STAKING_PROGRAM_ID,
-    );
+    let userPoolKey = await getUserPoolKey(userAddress);
+    let streamRet = await getStreamPayoutAccounts(userAddress, globalAuthority, program, connection);
+    let receiptRet = await getUnstakeReceipts(userAddress, mint, program, connection);
 
     let tx = new Transaction();
 
     if (ret.instructions.length > 0) ret.instructions.map((ix) => tx.add(ix));
+    if (rewardRet.instructions.length > 0) rewardRet.instructions.map((ix) => tx.add(ix));
+    if (streamRet.instructions.length > 0) streamRet.instructions.map((ix) => tx.add(ix));
+    if (receiptRet.instructions.length > 0) receiptRet.instructions.map((ix) => tx.add(ix));
     console.log('==> Withdrawing ... ', mint.toBase58());
 
     tx.add(program.instruction.withdrawNftFromPool(
@@ -676,11 +1114,219 @@ export const createWithdrawNftTx = async (
             userNftTokenAccount: userTokenAccount,
             destNftTokenAccount,
             rewardVault,
+            rewardMint: rewardMint,
+            userVault,
+            userRewardAccount: rewardRet.destinationAccounts[0],
+            nftMint: mint,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            rewardTokenProgram,
+            lastReceipt: receiptRet.lastReceipt,
+            stakeReceipt: receiptRet.stakeReceipt,
+        },
+        remainingAccounts: streamRet.remainingAccounts,
+        instructions: [],
+        signers: [],
+    }));
+
+    return tx;
+}
+
+
+export const createWithdrawNftsBatchTx = async (
+    mints: PublicKey[],
+    userAddress: PublicKey,
+    program: anchor.Program,
+    connection: Connection,
+) => {
+    const rewardMint = await getRewardMint(program);
+    const rewardTokenProgram = await getMintTokenProgram(rewardMint, connection);
+    let ret = await getATokenAccountsNeedCreate(
+        connection,
+        userAddress,
+        userAddress,
+        mints
+    );
+
+    const [userVault] = await getUserVaultKey(userAddress);
+    let rewardRet = await getATokenAccountsNeedCreate(
+        connection,
+        userAddress,
+        userVault,
+        [rewardMint],
+        rewardTokenProgram,
+    );
+
+    const [globalAuthority, bump] = await getPoolAuthority();
+    let rewardVault = await getAssociatedTokenAccount(globalAuthority, rewardMint, rewardTokenProgram);
+
+    let userPoolKey = await getUserPoolKey(userAddress);
+    let streamRet = await getStreamPayoutAccounts(userAddress, globalAuthority, program, connection);
+    let receiptRet = await getBatchUnstakeReceipts(userAddress, mints, program, connection);
+
+    // Every stream's payout accounts first, then [mint, user token account, pool token account, receipt, last receipt] per NFT
+    let remainingAccounts = streamRet.remainingAccounts;
+    for (const [index, mint] of mints.entries()) {
+        console.log("User NFT = ", mint.toBase58(), ret.destinationAccounts[index].toBase58());
+        remainingAccounts.push(
+            { pubkey: mint, isSigner: false, isWritable: false },
+            { pubkey: ret.destinationAccounts[index], isSigner: false, isWritable: true },
+            { pubkey: await getAssociatedTokenAccount(globalAuthority, mint), isSigner: false, isWritable: true },
+            { pubkey: receiptRet.stakeReceipts[index], isSigner: false, isWritable: true },
+            { pubkey: receiptRet.lastReceipts[index], isSigner: false, isWritable: true },
+        );
+    }
+
+    let tx = new Transaction();
+
+    if (ret.instructions.length > 0) ret.instructions.map((ix) => tx.add(ix));
+    if (rewardRet.instructions.length > 0) rewardRet.instructions.map((ix) => tx.add(ix));
+    if (streamRet.instructions.length > 0) streamRet.instructions.map((ix) => tx.add(ix));
+    if (receiptRet.instructions.length > 0) receiptRet.instructions.map((ix) => tx.add(ix));
+    console.log('==> Withdrawing ... ', mints.length);
+
+    tx.add(program.instruction.withdrawNftsBatch(
+        bump, {
+        accounts: {
+            owner: userAddress,
+            userPool: userPoolKey,
+            globalAuthority,
+            rewardVault,
+            rewardMint: rewardMint,
             userVault,
-            userRewardAccount: ret.destinationAccounts[1],
+            userRewardAccount: rewardRet.destinationAccounts[0],
+            tokenProgram: TOKEN_PROGRAM_ID,
+            rewardTokenProgram,
+        },
+        remainingAccounts,
+        instructions: [],
+        signers: [],
+    }));
+
+    return tx;
+}
+
+export const createEmergencyWithdrawNftTx = async (
+    mint: PublicKey,
+    userAddress: PublicKey,
+    program: anchor.Program,
+    connection: Connection,
+) => {
+    let ret = await getATokenAccountsNeedCreate(
+        connection,
+        userAddress,
+        userAddress,
+        [mint]
+    );
+    let userTokenAccount = ret.destinationAccounts[0];
+
+    const [globalAuthority, bump] = await getPoolAuthority();
+    let destNftTokenAccount = await getAssociatedTokenAccount(globalAuthority, mint);
+
+    let userPoolKey = await getUserPoolKey(userAddress);
+    let receiptRet = await getUnstakeReceipts(userAddress, mint, program, connection);
+
+    let tx = new Transaction();
+
+    if (ret.instructions.length > 0) ret.instructions.map((ix) => tx.add(ix));
+    if (receiptRet.instructions.length > 0) receiptRet.instructions.map((ix) => tx.add(ix));
+    console.log('==> Emergency Withdrawing ... ', mint.toBase58());
+
+    tx.add(program.instruction.emergencyWithdrawNft(
+        bump, {
+        accounts: {
+            owner: userAddress,
+            userPool: userPoolKey,
+            globalAuthority,
+            userNftTokenAccount: userTokenAccount,
+            destNftTokenAccount,
             nftMint: mint,
             tokenProgram: TOKEN_PROGRAM_ID,
+            lastReceipt: receiptRet.lastReceipt,
+            stakeReceipt: receiptRet.stakeReceipt,
+        },
+        instructions: [],
+        signers: [],
+    }));
+
+    return tx;
+}
+
+// Receipts of the unstaked NFT and of the user pool's last entry, which takes its place.
+// NFTs staked before receipts existed get theirs issued first.
+export const getUnstakeReceipts = async (
+    userAddress: PublicKey,
+    mint: PublicKey,
+    program: anchor.Program,
+    connection: Connection,
+) => {
+    const userPool: UserPool = await getUserPoolState(userAddress, program);
+    const lastMint = userPool.staking[userPool.stakedCount.toNumber() - 1].mint;
+    const [globalAuthority] = await getPoolAuthority();
+    let userPoolKey = await getUserPoolKey(userAddress);
+
+    let instructions = [];
+    let receipts = [];
+    for (const nftMint of lastMint.equals(mint) ? [mint] : [mint, lastMint]) {
+        const [stakeReceipt] = await getStakeReceiptKey(nftMint);
+        receipts.push(stakeReceipt);
+        if (await isExistAccount(stakeReceipt, connection)) continue;
+        instructions.push(program.instruction.issueStakeReceipt({
+            accounts: {
+                owner: userAddress,
+                userPool: userPoolKey,
+                globalAuthority,
+                nftMint,
+                stakeReceipt,
+                systemProgram: SystemProgram.programId,
+            },
+            instructions: [],
+            signers: [],
+        }));
+    }
+    return { instructions, stakeReceipt: receipts[0], lastReceipt: receipts[receipts.length - 1] };
+}
+
+export const createClaimRewardsTx = async (
+    userAddress: PublicKey,
+    program: anchor.Program,
+    connection: Connection,
+) => {
+    const rewardMint = await getRewardMint(program);
+    const rewardTokenProgram = await getMintTokenProgram(rewardMint, connection);
+    const [userVault] = await getUserVaultKey(userAddress);
+    let ret = await getATokenAccountsNeedCreate(
+        connection,
+        userAddress,
+        userVault,
+        [rewardMint],
+        rewardTokenProgram,
+    );
+
+    const [globalAuthority, bump] = await getPoolAuthority();
+    let rewardVault = await getAssociatedTokenAccount(globalAuthority, rewardMint, rewardTokenProgram);
+
+    let userPoolKey = await getUserPoolKey(userAddress);
+    let streamRet = await getStreamPayoutAccounts(userAddress, globalAuthority, program, connection);
+
+    let tx = new Transaction();
+
+    if (ret.instructions.length > 0) ret.instructions.map((ix) => tx.add(ix));
+    if (streamRet.instructions.length > 0) streamRet.instructions.map((ix) => tx.add(ix));
+    console.log('==> Claiming Rewards ... ');
+
+    tx.add(program.instruction.claimRewards(
+        bump, {
+        accounts: {
+            owner: userAddress,
+            userPool: userPoolKey,
+            globalAuthority,
+            rewardVault,
+            userVault,
+            userRewardAccount: ret.destinationAccounts[0],
+            rewardMint,
+            tokenProgram: rewardTokenProgram,
         },
+        remainingAccounts: streamRet.remainingAccounts,
         instructions: [],
         signers: [],
     }));
@@ -689,23 +1335,86 @@ export const createWithdrawNftTx = async (
 }
 
 
+export const createCloseUserPoolTx = async (
+    userAddress: PublicKey,
+    program: anchor.Program,
+    connection: Connection,
+) => {
+    const [globalAuthority] = await getPoolAuthority();
+    let userPoolKey = await getUserPoolKey(userAddress);
+
+    let tx = new Transaction();
+    console.log('==> Closing User Pool ... ', userPoolKey.toBase58());
+
+    tx.add(program.instruction.closeUserPool({
+        accounts: {
+            owner: userAddress,
+            userPool: userPoolKey,
+            globalAuthority,
+        },
+        instructions: [],
+        signers: [],
+    }));
+
+    return tx;
+}
+
+// Closes the vault of the selected pool, the vaults of other pools are left alone
+export const createCloseUserVaultTx = async (
+    userAddress: PublicKey,
+    program: anchor.Program,
+    connection: Connection,
+) => {
+    const rewardMint = await getRewardMint(program);
+    const tokenProgram = await getMintTokenProgram(rewardMint, connection);
+    const [globalAuthority] = await getPoolAuthority();
+    const [userVault] = await getUserVaultKey(userAddress);
+    // The vault's reward token account is closed with it, a vault that never received rewards gets one first
+    let ret = await getATokenAccountsNeedCreate(
+        connection,
+        userAddress,
+        userVault,
+        [rewardMint],
+        tokenProgram,
+    );
+
+    let tx = new Transaction();
+    console.log('==> Closing User Vault ... ', userVault.toBase58());
+
+    if (ret.instructions.length > 0) ret.instructions.map((ix) => tx.add(ix));
+    tx.add(program.instruction.closeUserVault({
+        accounts: {
+            owner: userAddress,
+            globalAuthority,
+            userVault,
+            userTokenAccount: ret.destinationAccounts[0],
+            rewardMint,
+            tokenProgram,
+        },
+        instructions: [],
+        signers: [],
+    }));
+
+    return tx;
+}
+
 export const createWithdrawTx = async (
     userAddress: PublicKey,
     amount: number,
     program: anchor.Program,
     connection: Connection,
 ) => {
-    const [globalAuthority, bump] = await PublicKey.findProgramAddress(
-        [Buffer.from(GLOBAL_AUTHORITY_SEED)],
-        STAKING_PROGRAM_ID
-    );
-    let rewardVault = await getAssociatedTokenAccount(globalAuthority, AMMO_TOKEN_MINT);
+    const rewardMint = await getRewardMint(program);
+    const rewardTokenProgram = await getMintTokenProgram(rewardMint, connection);
+    const [globalAuthority, bump] = await getPoolAuthority();
+    let rewardVault = await getAssociatedTokenAccount(globalAuthority, rewardMint, rewardTokenProgram);
 
     let ret = await getATokenAccountsNeedCreate(
         connection,
         userAddress,
         userAddress,
-        [AMMO_TOKEN_MINT]
+        [rewardMint],
+        rewardTokenProgram,
     );
 
     let tx = new Transaction();
@@ -719,7 +1428,120 @@ export const createWithdrawTx = async (
             globalAuthority,
             rewardVault,
             userRewardAccount: ret.destinationAccounts[0],
-            tokenProgram: TOKEN_PROGRAM_ID,
+            rewardMint,
+            tokenProgram: rewardTokenProgram,
+        },
+        instructions: [],
+        signers: [],
+    }));
+
+    return tx;
+}
+
+export const createQueueWithdrawalTx = async (
+    userAddress: PublicKey,
+    amount: number,
+    program: anchor.Program,
+    connection: Connection,
+) => {
+    const rewardMint = await getRewardMint(program);
+    const rewardTokenProgram = await getMintTokenProgram(rewardMint, connection);
+    const [globalAuthority] = await getPoolAuthority();
+    const globalPool: GlobalPool = await getGlobalState(program);
+    const [pendingWithdrawal] = await PublicKey.findProgramAddress(
+        [Buffer.from(PENDING_WITHDRAWAL_SEED), globalAuthority.toBuffer(), globalPool.withdrawalCount.toArrayLike(Buffer, "le", 8)],
+        STAKING_PROGRAM_ID
+    );
+
+    let ret = await getATokenAccountsNeedCreate(
+        connection,
+        userAddress,
+        userAddress,
+        [rewardMint],
+        rewardTokenProgram,
+    );
+
+    let tx = new Transaction();
+    console.log('==> Queueing Withdrawal ... ', amount);
+
+    if (ret.instructions.length > 0) ret.instructions.map((ix) => tx.add(ix));
+    tx.add(program.instruction.queueWithdrawal(
+        new anchor.BN(amount * AMMO_TOKEN_DECIMAL), {
+        accounts: {
+            owner: userAddress,
+            globalAuthority,
+            pendingWithdrawal,
+            userRewardAccount: ret.destinationAccounts[0],
+            systemProgram: SystemProgram.programId,
+            rent: SYSVAR_RENT_PUBKEY,
+        },
+        instructions: [],
+        signers: [],
+    }));
+
+    return tx;
+}
+
+export const createCancelWithdrawalTx = async (
+    userAddress: PublicKey,
+    id: number,
+    program: anchor.Program,
+    connection: Connection,
+) => {
+    const [globalAuthority] = await getPoolAuthority();
+    const [pendingWithdrawal] = await PublicKey.findProgramAddress(
+        [Buffer.from(PENDING_WITHDRAWAL_SEED), globalAuthority.toBuffer(), new anchor.BN(id).toArrayLike(Buffer, "le", 8)],
+        STAKING_PROGRAM_ID
+    );
+    const pending = await program.account.pendingWithdrawal.fetch(pendingWithdrawal) as unknown as PendingWithdrawal;
+
+    let tx = new Transaction();
+    console.log('==> Cancelling Withdrawal ... ', id);
+
+    tx.add(program.instruction.cancelWithdrawal({
+        accounts: {
+            owner: userAddress,
+            globalAuthority,
+            pendingWithdrawal,
+            proposer: pending.proposer,
+        },
+        instructions: [],
+        signers: [],
+    }));
+
+    return tx;
+}
+
+export const createExecuteWithdrawalTx = async (
+    userAddress: PublicKey,
+    id: number,
+    program: anchor.Program,
+    connection: Connection,
+) => {
+    const rewardMint = await getRewardMint(program);
+    const rewardTokenProgram = await getMintTokenProgram(rewardMint, connection);
+    const [globalAuthority, bump] = await getPoolAuthority();
+    let rewardVault = await getAssociatedTokenAccount(globalAuthority, rewardMint, rewardTokenProgram);
+    const [pendingWithdrawal] = await PublicKey.findProgramAddress(
+        [Buffer.from(PENDING_WITHDRAWAL_SEED), globalAuthority.toBuffer(), new anchor.BN(id).toArrayLike(Buffer, "le", 8)],
+        STAKING_PROGRAM_ID
+    );
+    const pending = await program.account.pendingWithdrawal.fetch(pendingWithdrawal) as unknown as PendingWithdrawal;
+
+    let tx = new Transaction();
+    console.log('==> Executing Withdrawal ... ', id);
+
+    tx.add(program.instruction.executeWithdrawal(
+        bump, {
+        accounts: {
+            owner: userAddress,
+            globalAuthority,
+            pendingWithdrawal,
+            proposer: pending.proposer,
+            rewardVault,
+            destination: pending.destination,
+            rewardMint,
+            tokenProgram: rewardTokenProgram,
         },
         instructions: [],
         signers: [],
@@ -741,6 +1563,9 @@ export const getUserPoolInfo = async (
                 stakedTime: info.stakedTime.toNumber(),
                 lockTime: info.lockTime.toNumber(),
                 duration: info.duration.toNumber(),
+                reward: info.reward.toString(),
+                lastClaimed: info.lastClaimed.toNumber(),
+                rewardMode: info.rewardMode,
             }
         }),
     };
@@ -749,8 +1574,32 @@ export const getUserPoolInfo = async (
 export const getGlobalInfo = async () => {
     const globalPool: GlobalPool = await getGlobalState(program);
     const result = {
+        version: globalPool.version,
+        poolId: globalPool.poolId.toNumber(),
         admin: globalPool.superAdmin.toBase58(),
         totalStakedCount: globalPool.totalStakedCount.toNumber(),
+        rewardMint: globalPool.rewardMint.toBase58(),
+        rewardMode: globalPool.rewardMode,
+        rewardRate: globalPool.rewardRate.toString(),
+        rewardFunding: globalPool.rewardFunding,
+        emissionCap: globalPool.emissionCap.toString(),
+        emitted: globalPool.emitted.toString(),
+        rewardOwed: globalPool.rewardOwed.toString(),
+        streamCount: globalPool.streamCount.toNumber(),
+        earlyUnstakePolicy: globalPool.earlyUnstakePolicy,
+        earlyUnstakePenalty: globalPool.earlyUnstakePenalty,
+        burnPenalty: globalPool.burnPenalty,
+        rewardLiability: globalPool.rewardLiability.toString(),
+        paused: globalPool.paused,
+        emergency: globalPool.emergency,
+        pendingAdmin: globalPool.pendingAdmin.toBase58(),
+        roles: globalPool.roles.map((role) => role.toBase58()),
+        withdrawThreshold: globalPool.withdrawThreshold.toString(),
+        withdrawDelay: globalPool.withdrawDelay.toNumber(),
+        pendingWithdrawThreshold: globalPool.pendingWithdrawThreshold.toString(),
+        pendingWithdrawDelay: globalPool.pendingWithdrawDelay.toNumber(),
+        pendingTimelockAfter: globalPool.pendingTimelockAfter.toNumber(),
+        withdrawalCount: globalPool.withdrawalCount.toNumber(),
     };
 
     return result;
@@ -764,10 +1613,7 @@ export const getAllNFTs = async (rpc?: string) => {
 export const getGlobalState = async (
     program: anchor.Program,
 ): Promise<GlobalPool | null> => {
-    const [globalAuthority, _] = await PublicKey.findProgramAddress(
-        [Buffer.from(GLOBAL_AUTHORITY_SEED)],
-        STAKING_PROGRAM_ID
-    );
+    const [globalAuthority, _] = await getPoolAuthority();
     try {
         let globalState = await program.account.globalPool.fetch(globalAuthority);
         return globalState as unknown as GlobalPool;
@@ -776,23 +1622,203 @@ export const getGlobalState = async (
     }
 }
 
-export const getUserPoolState = async (
+export const getRewardMint = async (
+    program: anchor.Program,
+): Promise<PublicKey> => {
+    const globalPool: GlobalPool = await getGlobalState(program);
+    return globalPool.rewardMint;
+}
+
+// Receipts for withdrawing several NFTs in order. Every withdrawal moves the user pool's
+// last entry into the freed place, so each NFT is paired with the last entry at that point.
+export const getBatchUnstakeReceipts = async (
     userAddress: PublicKey,
+    mints: PublicKey[],
     program: anchor.Program,
-): Promise<UserPool | null> => {
-    let userPoolKey = await anchor.web3.PublicKey.createWithSeed(
-        userAddress,
-        "user-pool",
-        STAKING_PROGRAM_ID,
-    );
+    connection: Connection,
+) => {
+    const userPool: UserPool = await getUserPoolState(userAddress, program);
+    let staked = userPool.staking.slice(0, userPool.stakedCount.toNumber()).map(({ mint }) => mint);
+    const [globalAuthority] = await getPoolAuthority();
+    let userPoolKey = await getUserPoolKey(userAddress);
+
+    let instructions = [];
+    let stakeReceipts = [];
+    let lastReceipts = [];
+    let checked = new Set<string>();
+    for (const mint of mints) {
+        const index = staked.findIndex((nftMint) => nftMint.equals(mint));
+        if (index < 0) throw 'Error: Nft is not staked by user';
+        const lastMint = staked[staked.length - 1];
+        for (const nftMint of [mint, lastMint]) {
+            const [stakeReceipt] = await getStakeReceiptKey(nftMint);
+            if (nftMint.equals(mint)) stakeReceipts.push(stakeReceipt);
+            if (nftMint.equals(lastMint)) lastReceipts.push(stakeReceipt);
+            if (checked.has(nftMint.toBase58())) continue;
+            checked.add(nftMint.toBase58());
+            if (await isExistAccount(stakeReceipt, connection)) continue;
+            instructions.push(program.instruction.issueStakeReceipt({
+                accounts: {
+                    owner: userAddress,
+                    userPool: userPoolKey,
+                    globalAuthority,
+                    nftMint,
+                    stakeReceipt,
+                    systemProgram: SystemProgram.programId,
+                },
+                instructions: [],
+                signers: [],
+            }));
+        }
+        staked[index] = lastMint;
+        staked.pop();
+    }
+    return { instructions, stakeReceipts, lastReceipts };
+}
+
+export const getRewardStreams = async (
+    program: anchor.Program,
+): Promise<{ address: PublicKey, stream: RewardStream }[]> => {
+    const globalPool: GlobalPool = await getGlobalState(program);
+    const [globalAuthority] = await getPoolAuthority();
+    let streams = [];
+    for (let index = 0; index < globalPool.streamCount.toNumber(); index++) {
+        const [address] = await getRewardStreamKey(globalAuthority, index);
+        const stream = await program.account.rewardStream.fetch(address);
+        streams.push({ address, stream: stream as unknown as RewardStream });
+    }
+    return streams;
+}
+
+// Every stream is paid as [stream, pool vault, mint, owner's token account, token program],
+// creating the owner's token accounts that don't exist yet
+export const getStreamPayoutAccounts = async (
+    userAddress: PublicKey,
+    globalAuthority: PublicKey,
+    program: anchor.Program,
+    connection: Connection,
+) => {
+    let instructions = [];
+    let remainingAccounts = [];
+    for (const { address, stream } of await getRewardStreams(program)) {
+        const tokenProgram = await getMintTokenProgram(stream.mint, connection);
+        let ret = await getATokenAccountsNeedCreate(
+            connection,
+            userAddress,
+            userAddress,
+            [stream.mint],
+            tokenProgram,
+        );
+        instructions.push(...ret.instructions);
+        remainingAccounts.push(
+            { pubkey: address, isSigner: false, isWritable: false },
+            { pubkey: await getAssociatedTokenAccount(globalAuthority, stream.mint, tokenProgram), isSigner: false, isWritable: true },
+            { pubkey: stream.mint, isSigner: false, isWritable: false },
+            { pubkey: ret.destinationAccounts[0], isSigner: false, isWritable: true },
+            { pubkey: tokenProgram, isSigner: false, isWritable: false },
+        );
+    }
+    return { instructions, remainingAccounts };
+}
+
+export const getStakeReceipt = async (
+    mint: PublicKey,
+    program: anchor.Program,
+): Promise<StakeReceipt | null> => {
+    const [stakeReceipt] = await getStakeReceiptKey(mint);
     try {
-        let userPoolState = await program.account.userPool.fetch(userPoolKey);
-        return userPoolState as unknown as UserPool;
+        let receipt = await program.account.stakeReceipt.fetch(stakeReceipt);
+        return receipt as unknown as StakeReceipt;
     } catch {
         return null;
     }
 }
 
+export const getUserPoolState = async (
+    userAddress: PublicKey,
+    program: anchor.Program,
+): Promise<UserPool | null> => {
+    let userPoolKey = await getUserPoolKey(userAddress);
+    const userPoolInfo = await program.provider.connection.getAccountInfo(userPoolKey);
+    if (!userPoolInfo || !isUserPoolSize(userPoolInfo.data.length)) return null;
+    return decodeUserPool(userPoolInfo.data);
+}
+
+// Decode a user pool header and its StakedData entries, which grow past the IDL's fixed header
+export const decodeUserPool = (data: Buffer): UserPool => {
+    const owner = new PublicKey(data.slice(8, 40));
+
+    let buf = data.slice(40, 48).reverse();
+    const stakedCount = new anchor.BN(buf);
+    const version = data[48];
+    const pool = new PublicKey(data.slice(49, 81));
+    buf = data.slice(144, 152).reverse();
+    const rewardOwed = new anchor.BN(buf);
+
+    let streamOwed = [];
+    for (let j = 0; j < 3; j++) {
+        buf = data.slice(152 + j * 8, 160 + j * 8).reverse();
+        streamOwed.push(new anchor.BN(buf));
+    }
+
+    let staking = [];
+    for (let i = 0; i < stakedCount.toNumber(); i++) {
+        const offset = i * STAKED_DATA_SIZE + USER_POOL_HEADER_SIZE;
+        const mint = new PublicKey(data.slice(offset, offset + 32));
+
+        buf = data.slice(offset + 32, offset + 40).reverse();
+        const stakedTime = new anchor.BN(buf);
+        buf = data.slice(offset + 40, offset + 48).reverse();
+        const lockTime = new anchor.BN(buf);
+        buf = data.slice(offset + 48, offset + 56).reverse();
+        const duration = new anchor.BN(buf);
+        buf = data.slice(offset + 56, offset + 64).reverse();
+        const reward = new anchor.BN(buf);
+        buf = data.slice(offset + 64, offset + 72).reverse();
+        const lastClaimed = new anchor.BN(buf);
+        const rewardMode = data[offset + 72];
+        buf = data.slice(offset + 80, offset + 96).reverse();
+        const rewardPerNftPaid = new anchor.BN(buf);
+
+        let streams = [];
+        for (let j = 0; j < 3; j++) {
+            buf = data.slice(offset + 96 + j * 16, offset + 104 + j * 16).reverse();
+            const streamReward = new anchor.BN(buf);
+            buf = data.slice(offset + 104 + j * 16, offset + 112 + j * 16).reverse();
+            const paid = new anchor.BN(buf);
+            streams.push({ reward: streamReward, paid });
+        }
+
+        staking.push({
+            mint,
+            stakedTime,
+            lockTime,
+            duration,
+            reward,
+            lastClaimed,
+            rewardMode,
+            rewardPerNftPaid,
+            streams,
+        })
+    }
+
+    return {
+        owner,
+        stakedCount,
+        version,
+        pool,
+        reserved: [],
+        rewardOwed,
+        streamOwed,
+        staking,
+    };
+}
+
+// Current user pools are the header plus a whole number of entries, at least the initial capacity
+const isUserPoolSize = (size: number) => {
+    return size >= USER_POOL_SIZE && (size - USER_POOL_HEADER_SIZE) % STAKED_DATA_SIZE === 0;
+}
+
 export const getAllStakedNFTs = async (connection: Connection, rpcUrl: string | undefined) => {
     let solConnection = connection;
 
@@ -800,12 +1826,16 @@ export const getAllStakedNFTs = async (connection: Connection, rpcUrl: string |
         solConnection = new anchor.web3.Connection(rpcUrl, "confirmed");
     }
 
+    // User pools grow as NFTs get staked, so match their discriminator instead of a fixed size
     let poolAccounts = await solConnection.getProgramAccounts(
         STAKING_PROGRAM_ID,
         {
             filters: [
                 {
-                    dataSize: USER_POOL_SIZE,
+                    memcmp: {
+                        offset: 0,
+                        bytes: anchor.utils.bytes.bs58.encode(anchor.BorshAccountsCoder.accountDiscriminator("UserPool")),
+                    },
                 },
             ]
         }
@@ -818,35 +1848,9 @@ export const getAllStakedNFTs = async (connection: Connection, rpcUrl: string |
     try {
         for (let idx = 0; idx < poolAccounts.length; idx++) {
             let data = poolAccounts[idx].account.data;
-            const owner = new PublicKey(data.slice(8, 40));
-
-            let buf = data.slice(40, 48).reverse();
-            const stakedCount = new anchor.BN(buf);
-
-            let staking = [];
-            for (let i = 0; i < stakedCount.toNumber(); i++) {
-                const mint = new PublicKey(data.slice(i * 56 + 48, i * 56 + 80));
-
-                buf = data.slice(i * 56 + 80, i * 56 + 88).reverse();
-                const stakedTime = new anchor.BN(buf);
-                buf = data.slice(i * 56 + 88, i * 56 + 96).reverse();
-                const lockTime = new anchor.BN(buf);
-                buf = data.slice(i * 56 + 96, i * 56 + 104).reverse();
-                const rewardAmount = new anchor.BN(buf);
-
-                staking.push({
-                    mint,
-                    stakedTime,
-                    lockTime,
-                    rewardAmount,
-                })
-            }
-
-            result.push({
-                owner,
-                stakedCount,
-                staking,
-            });
+            // Older layouts wait for migrate_user_pool
+            if (!isUserPoolSize(data.length)) continue;
+            result.push(decodeUserPool(data));
         }
     } catch (e) {
         console.log(e);
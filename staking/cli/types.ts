@@ -3,29 +3,191 @@ import { PublicKey } from '@solana/web3.js';
 
 export const GLOBAL_AUTHORITY_SEED = "global-authority";
 export const VAULT_SEED = "vault-seed";
+export const REWARD_CONFIG_SEED = "reward-config";
+export const COLLECTION_REGISTRY_SEED = "collection-registry";
+export const PENDING_WITHDRAWAL_SEED = "pending-withdrawal";
+export const REWARD_STREAM_SEED = "reward-stream";
+export const STAKE_RECEIPT_SEED = "receipt";
+export const USER_POOL_SEED = "user-pool";
 
 export const STAKING_PROGRAM_ID = new PublicKey("2RbwYVj8gmYf8TRNukd34fGJgT7X4X4K3t6gLGwJkNQD");
-export const AMMO_TOKEN_MINT = new PublicKey("H3rmqbVz8NTCkGABeue3yc9PgioL2i1RPrQM45itdKMu");
+// Reward mint new pools are created with, pools keep their own in GlobalPool.rewardMint
+export const AMMO_TOKEN_MINT = new PublicKey("H1X2quMc8ZesCE4afDQhWAmFssENprmmz9a6SXvHWPoN");
 export const AMMO_TOKEN_DECIMAL = 1_000_000_000; 
 
-export const USER_POOL_SIZE = 5648;     // 8 + 5640
+export const REWARD_MODE_TIER = 0;
+export const REWARD_MODE_CONTINUOUS = 1;
+
+export const REWARD_FUNDING_VAULT = 0;
+export const REWARD_FUNDING_MINT = 1;
+
+export const PAUSE_STAKING = 1 << 0;
+export const PAUSE_UNSTAKING = 1 << 1;
+export const PAUSE_VAULT = 1 << 2;
+export const PAUSE_FUSION = 1 << 3;
+
+export const ROLE_TREASURER = 0;
+export const ROLE_OPERATOR = 1;
+export const ROLE_PAUSER = 2;
+export const ROLE_FUSION_SIGNER = 3;
+
+export const EARLY_UNSTAKE_REJECT = 0;
+export const EARLY_UNSTAKE_PENALTY = 1;
+
+// Collections and reward schedule the program is initialized with
+export const DEFAULT_COLLECTIONS = [
+    {
+        address: new PublicKey("9VYHEsB5k9CA1gbUbxJ7CsnxhV9GgegjqiCCSy1APNF1"),
+        stakeable: false,
+        fusable: true,
+        verifyCreator: true,
+        verifyCollection: false,
+    },
+    {
+        address: new PublicKey("BGSpWrrB6FtnSPZ8PhS7dXiUVLeQyCqxRz3t8cGK2NxH"),
+        stakeable: true,
+        fusable: false,
+        verifyCreator: true,
+        verifyCollection: false,
+    },
+];
+export const DEFAULT_REWARD_TIERS = [[1, 90], [5, 540], [15, 1620], [30, 3150]].map(([days, ammo]) => ({
+    duration: new anchor.BN(days),
+    reward: new anchor.BN(ammo).mul(new anchor.BN(AMMO_TOKEN_DECIMAL)),
+    active: true,
+}));
+// Treasury withdrawals above the threshold, 0 for a new pool, wait this long
+export const DEFAULT_WITHDRAW_DELAY = 86400;
+
+// User pools are a header followed by StakedData entries and grow as NFTs get staked
+export const USER_POOL_HEADER_SIZE = 176;   // 8 + 168
+export const STAKED_DATA_SIZE = 144;
+export const USER_POOL_SIZE = 10112;    // 8 + 168 + 144 * 69, as much as a PDA can be created with
+export const LEGACY_USER_POOL_SIZE = 5648;  // 8 + 5640, before versioning
 
 export interface GlobalPool {
-    // 8 + 40
+    // 8 + 456
+    version: number,                // 1
     superAdmin: PublicKey,          // 32
     totalStakedCount: anchor.BN,    // 8
+    rewardMode: number,             // 1
+    rewardRate: anchor.BN,          // 8
+    rewardPerNft: anchor.BN,        // 16
+    rateUpdatedTime: anchor.BN,     // 8
+    earlyUnstakePolicy: number,     // 1
+    earlyUnstakePenalty: number,    // 1
+    burnPenalty: boolean,           // 1
+    rewardLiability: anchor.BN,     // 8
+    continuousStakedCount: anchor.BN,   // 8
+    continuousRewardPaid: anchor.BN,    // 16
+    paused: number,                 // 1
+    emergency: boolean,             // 1
+    pendingAdmin: PublicKey,        // 32
+    roles: PublicKey[],             // 32 * 4
+    withdrawThreshold: anchor.BN,   // 8
+    withdrawDelay: anchor.BN,       // 8
+    withdrawalCount: anchor.BN,     // 8
+    rewardMint: PublicKey,          // 32
+    poolId: anchor.BN,              // 8
+    rewardFunding: number,          // 1
+    emissionCap: anchor.BN,         // 8
+    emitted: anchor.BN,             // 8
+    streamCount: anchor.BN,         // 8
+    pendingWithdrawThreshold: anchor.BN,    // 8
+    pendingWithdrawDelay: anchor.BN,        // 8
+    pendingTimelockAfter: anchor.BN,        // 8
+    rewardOwed: anchor.BN,          // 8
+    reserved: anchor.BN[],          // 8 * 8
+}
+
+export interface RewardStream {
+    // 8 + 268
+    pool: PublicKey,                // 32
+    index: number,                  // 1
+    mint: PublicKey,                // 32
+    rewardMode: number,             // 1
+    rewardRate: anchor.BN,          // 8
+    rewardPerNft: anchor.BN,        // 8
+    rateUpdatedTime: anchor.BN,     // 8
+    tierCount: anchor.BN,           // 8
+    tiers: RewardTier[],            // 17 * 10
+}
+
+export interface PendingWithdrawal {
+    // 8 + 88
+    id: anchor.BN,              // 8
+    proposer: PublicKey,        // 32
+    destination: PublicKey,     // 32
+    amount: anchor.BN,          // 8
+    executeAfter: anchor.BN,    // 8
+}
+
+export interface AllowedCollection {
+    address: PublicKey,         // 32
+    stakeable: boolean,         // 1
+    fusable: boolean,           // 1
+    verifyCreator: boolean,     // 1
+    verifyCollection: boolean,  // 1
+}
+
+export interface CollectionRegistry {
+    // 8 + 368
+    collectionCount: anchor.BN,         // 8
+    collections: AllowedCollection[],   // 36 * 10
 }
 
 export interface StakedData {
     mint: PublicKey,            // 32
     stakedTime: anchor.BN,      // 8
     lockTime: anchor.BN,        // 8
-    duration: anchor.BN,    // 8
+    duration: anchor.BN,        // 8
+    reward: anchor.BN,          // 8
+    lastClaimed: anchor.BN,     // 8
+    rewardMode: number,         // 1
+    padding: number[],          // 7
+    rewardPerNftPaid: anchor.BN,    // 16
+    streams: StakedStream[],        // 16 * 3
+}
+
+export interface StakeReceipt {
+    // 8 + 138
+    owner: PublicKey,           // 32
+    pool: PublicKey,            // 32
+    nftMint: PublicKey,         // 32
+    index: anchor.BN,           // 8
+    stakedTime: anchor.BN,      // 8
+    lockTime: anchor.BN,        // 8
+    duration: anchor.BN,        // 8
+    reward: anchor.BN,          // 8
+    rewardMode: number,         // 1
+    bump: number,               // 1
+}
+
+export interface StakedStream {
+    reward: anchor.BN,          // 8
+    paid: anchor.BN,            // 8
+}
+
+export interface RewardTier {
+    duration: anchor.BN,        // 8
+    reward: anchor.BN,          // 8
+    active: boolean,            // 1
+}
+
+export interface RewardConfig {
+    // 8 + 178
+    tierCount: anchor.BN,       // 8
+    tiers: RewardTier[],        // 17 * 10
 }
 
 export interface UserPool {
-    // 8 + 5640
+    // 8 + 168, followed by the staked entries
     owner: PublicKey,               // 32
     stakedCount: anchor.BN,         // 8
-    staking: StakedData[],          // 56 * 100
+    version: number,                // 1
+    pool: PublicKey,                // 32
+    reserved: number[],             // 63
+    rewardOwed: anchor.BN,          // 8
+    streamOwed: anchor.BN[],        // 8 * 3
+    staking: StakedData[],          // 144 * staked count
 }
\ No newline at end of file
@@ -10,6 +10,7 @@ import {
 import { TOKEN_PROGRAM_ID, ASSOCIATED_TOKEN_PROGRAM_ID, Token, MintLayout } from "@solana/spl-token";
 
 export const METAPLEX = new PublicKey('metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s');
+export const TOKEN_2022_PROGRAM_ID = new PublicKey('TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb');
 
 export const getOwnerOfNFT = async (nftMintPk: PublicKey, connection: Connection): Promise<PublicKey> => {
     let tokenAccountPK = await getNFTTokenAccount(nftMintPk, connection);
@@ -79,11 +80,21 @@ export const getNFTTokenAccount = async (nftMintPk: PublicKey, connection: Conne
     return tokenAccount[0].pubkey;
 }
 
-export const getAssociatedTokenAccount = async (ownerPubkey: PublicKey, mintPk: PublicKey): Promise<PublicKey> => {
+/** SPL Token or Token-2022, whichever program owns the mint */
+export const getMintTokenProgram = async (mintPk: PublicKey, connection: Connection): Promise<PublicKey> => {
+    const mintInfo = await connection.getAccountInfo(mintPk);
+    return mintInfo ? mintInfo.owner : TOKEN_PROGRAM_ID;
+}
+
+export const getAssociatedTokenAccount = async (
+    ownerPubkey: PublicKey,
+    mintPk: PublicKey,
+    tokenProgram: PublicKey = TOKEN_PROGRAM_ID,
+): Promise<PublicKey> => {
     let associatedTokenAccountPubkey = (await PublicKey.findProgramAddress(
         [
             ownerPubkey.toBuffer(),
-            TOKEN_PROGRAM_ID.toBuffer(),
+            tokenProgram.toBuffer(),
             mintPk.toBuffer(), // mint address
         ],
         ASSOCIATED_TOKEN_PROGRAM_ID
@@ -96,10 +107,11 @@ export const getATokenAccountsNeedCreate = async (
     walletAddress: PublicKey,
     owner: PublicKey,
     nfts: PublicKey[],
+    tokenProgram: PublicKey = TOKEN_PROGRAM_ID,
 ) => {
     let instructions = [], destinationAccounts = [];
     for (const mint of nfts) {
-        const destinationPubkey = await getAssociatedTokenAccount(owner, mint);
+        const destinationPubkey = await getAssociatedTokenAccount(owner, mint, tokenProgram);
         const response = await connection.getAccountInfo(destinationPubkey);
         if (!response) {
             const createATAIx = createAssociatedTokenAccountInstruction(
@@ -107,6 +119,7 @@ export const getATokenAccountsNeedCreate = async (
                 walletAddress,
                 owner,
                 mint,
+                tokenProgram,
             );
             instructions.push(createATAIx);
         }
@@ -122,7 +135,8 @@ export const createAssociatedTokenAccountInstruction = (
     associatedTokenAddress: PublicKey,
     payer: PublicKey,
     walletAddress: PublicKey,
-    splTokenMintAddress: PublicKey
+    splTokenMintAddress: PublicKey,
+    tokenProgram: PublicKey = TOKEN_PROGRAM_ID,
 ) => {
     const keys = [
         { pubkey: payer, isSigner: true, isWritable: true },
@@ -134,7 +148,7 @@ export const createAssociatedTokenAccountInstruction = (
             isSigner: false,
             isWritable: false,
         },
-        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: tokenProgram, isSigner: false, isWritable: false },
         {
             pubkey: SYSVAR_RENT_PUBKEY,
             isSigner: false,
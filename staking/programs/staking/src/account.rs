@@ -1,14 +1,167 @@
 use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
+use std::cell::RefMut;
+use std::ops::{Deref, DerefMut};
 
 use crate::constants::*;
 use crate::error::*;
 
+/// State of a single staking pool, also the authority holding its vault and staked NFTs
 #[account]
 #[derive(Default)]
 pub struct GlobalPool {
-    // 8 + 40
+    // 8 + 456
+    pub version: u8,                // 1
     pub super_admin: Pubkey,        // 32
     pub total_staked_count: u64,    // 8
+    pub reward_mode: u8,            // 1
+    pub reward_rate: u64,           // 8
+    pub reward_per_nft: u128,       // 16
+    pub rate_updated_time: i64,     // 8
+    pub early_unstake_policy: u8,   // 1
+    pub early_unstake_penalty: u8,  // 1
+    pub burn_penalty: bool,         // 1
+    pub reward_liability: u64,      // 8
+    pub continuous_staked_count: u64,   // 8
+    pub continuous_reward_paid: u128,   // 16
+    pub paused: u8,                 // 1
+    pub emergency: bool,            // 1
+    pub pending_admin: Pubkey,      // 32
+    pub roles: [Pubkey; ROLE_COUNT],    // 32 * 4
+    pub withdraw_threshold: u64,    // 8
+    pub withdraw_delay: i64,        // 8
+    pub withdrawal_count: u64,      // 8
+    pub reward_mint: Pubkey,        // 32
+    pub pool_id: u64,               // 8
+    pub reward_funding: u8,         // 1
+    pub emission_cap: u64,          // 8
+    pub emitted: u64,               // 8
+    pub stream_count: u64,          // 8
+    pub pending_withdraw_threshold: u64,    // 8
+    pub pending_withdraw_delay: i64,        // 8
+    pub pending_timelock_after: i64,        // 8, 0 while no timelock change is queued
+    pub reward_owed: u64,           // 8, rewards the emission cap left unminted, see UserPool.reward_owed
+    pub reserved: [u64; 8],         // 64
+}
+
+impl GlobalPool {
+    // Continuous reward a single NFT has earned since the program started
+    pub fn current_reward_per_nft(&self, now: i64) -> u128 {
+        let elapsed = (now - self.rate_updated_time).max(0);
+        self.reward_per_nft + self.reward_rate as u128 * elapsed as u128
+    }
+
+    // Checkpoint accrual at the old rate before switching to the new one
+    pub fn set_reward_rate(&mut self, rate: u64, now: i64) {
+        self.reward_per_nft = self.current_reward_per_nft(now);
+        self.rate_updated_time = now;
+        self.reward_rate = rate;
+    }
+
+    // Rewards promised to stakers that haven't been paid out yet
+    pub fn outstanding_rewards(&self, now: i64) -> u128 {
+        let continuous = self.current_reward_per_nft(now) * self.continuous_staked_count as u128;
+        self.reward_liability as u128 + self.reward_owed as u128 + continuous - self.continuous_reward_paid
+    }
+
+    // Part of the reward vault not backing any outstanding reward
+    pub fn free_reward_balance(&self, vault_amount: u64, now: i64) -> u64 {
+        if self.reward_funding == REWARD_FUNDING_MINT {
+            return vault_amount;
+        }
+        (vault_amount as u128).saturating_sub(self.outstanding_rewards(now)) as u64
+    }
+
+    // Whether the vault, or the emission left under the cap, covers every outstanding reward
+    pub fn is_solvent(&self, vault_amount: u64, now: i64) -> bool {
+        let available = match self.reward_funding {
+            REWARD_FUNDING_MINT => self.emission_cap.saturating_sub(self.emitted),
+            _ => vault_amount,
+        };
+        available as u128 >= self.outstanding_rewards(now)
+    }
+
+    // Tightening the withdrawal timelock applies right away, loosening it is queued behind
+    // the current delay so the treasury can't skip the timelock. Returns when it applies.
+    pub fn set_withdraw_timelock(&mut self, threshold: u64, delay: i64, now: i64) -> Result<i64> {
+        require!(delay >= MIN_WITHDRAW_DELAY, StakingError::InvalidWithdrawDelay);
+        if threshold <= self.withdraw_threshold && delay >= self.withdraw_delay {
+            self.withdraw_threshold = threshold;
+            self.withdraw_delay = delay;
+            self.pending_timelock_after = 0;
+            return Ok(now);
+        }
+        self.pending_withdraw_threshold = threshold;
+        self.pending_withdraw_delay = delay;
+        self.pending_timelock_after = now + self.withdraw_delay;
+        Ok(self.pending_timelock_after)
+    }
+
+    pub fn apply_withdraw_timelock(&mut self, now: i64) -> Result<()> {
+        require!(self.pending_timelock_after != 0, StakingError::NoPendingTimelockChange);
+        require!(now >= self.pending_timelock_after, StakingError::WithdrawalTimelocked);
+        self.withdraw_threshold = self.pending_withdraw_threshold;
+        self.withdraw_delay = self.pending_withdraw_delay;
+        self.pending_timelock_after = 0;
+        Ok(())
+    }
+
+    // Count minted rewards against the emission cap, returns the part of `amount` that fits under it
+    pub fn record_emission(&mut self, amount: u64) -> u64 {
+        let mintable = amount.min(self.emission_cap.saturating_sub(self.emitted));
+        self.emitted += mintable;
+        mintable
+    }
+
+    pub fn commit_stake(&mut self, staked: &StakedData) {
+        if staked.reward_mode == REWARD_MODE_CONTINUOUS {
+            self.continuous_staked_count += 1;
+            self.continuous_reward_paid += staked.reward_per_nft_paid;
+        } else {
+            self.reward_liability += staked.reward;
+        }
+    }
+
+    pub fn settle_claim(&mut self, staked: &StakedData, paid: u64) {
+        if staked.reward_mode == REWARD_MODE_CONTINUOUS {
+            self.continuous_reward_paid += paid as u128;
+        } else {
+            self.reward_liability -= paid;
+        }
+    }
+
+    // Drop whatever the stake still had outstanding, paid out or not
+    pub fn release_stake(&mut self, staked: &StakedData) {
+        if staked.reward_mode == REWARD_MODE_CONTINUOUS {
+            self.continuous_staked_count -= 1;
+            self.continuous_reward_paid -= staked.reward_per_nft_paid;
+        } else {
+            self.reward_liability -= staked.reward - staked.accrued_reward(staked.last_claimed);
+        }
+    }
+
+    // The super admin holds every role
+    pub fn has_role(&self, key: &Pubkey, role: u8) -> bool {
+        if *key == self.super_admin {
+            return true;
+        }
+        let holder = self.roles[role as usize];
+        holder != Pubkey::default() && holder == *key
+    }
+
+    // Under the penalty policy locked stakes only pay out on unstake, so claiming
+    // right before an early unstake can't shrink the penalty
+    pub fn defers_claim(&self, staked: &StakedData, now: i64) -> bool {
+        staked.lock_time > now && self.early_unstake_penalty().is_some()
+    }
+
+    // Penalty percentage for unstaking before lock_time, None when it is rejected
+    pub fn early_unstake_penalty(&self) -> Option<u8> {
+        match self.early_unstake_policy {
+            EARLY_UNSTAKE_PENALTY => Some(self.early_unstake_penalty),
+            _ => None,
+        }
+    }
 }
 
 #[account]
@@ -19,6 +172,232 @@ pub struct UserVault {
     pub amount: u64,    // 8
 }
 
+impl UserVault {
+    /// Record tokens that arrived in the vault's token account
+    pub fn credit(&mut self, amount: u64) -> Result<()> {
+        self.amount = self.amount.checked_add(amount).ok_or(StakingError::AccountVaultOverflow)?;
+        Ok(())
+    }
+
+    /// Record tokens leaving the vault, which can't be more than it was credited
+    pub fn debit(&mut self, amount: u64) -> Result<()> {
+        self.amount = self.amount.checked_sub(amount).ok_or(StakingError::InsufficientAccountVault)?;
+        Ok(())
+    }
+}
+
+#[account]
+#[derive(Default)]
+pub struct PendingWithdrawal {
+    // 8 + 88
+    pub id: u64,                // 8
+    pub proposer: Pubkey,       // 32
+    pub destination: Pubkey,    // 32
+    pub amount: u64,            // 8
+    pub execute_after: i64,     // 8
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Default, Clone, Copy)]
+pub struct RewardTier {
+    pub duration: i64,      // 8
+    pub reward: u64,        // 8
+    pub active: bool,       // 1
+}
+
+#[account]
+#[derive(Default)]
+pub struct RewardConfig {
+    // 8 + 178
+    pub tier_count: u64,                            // 8
+    pub tiers: [RewardTier; MAX_REWARD_TIERS],      // 17 * 10
+}
+
+impl RewardConfig {
+    pub fn find_tier(
+        &self,
+        duration: i64,
+    ) -> Option<RewardTier> {
+        self.tiers[..self.tier_count as usize]
+            .iter()
+            .find(|tier| tier.active && tier.duration == duration)
+            .copied()
+    }
+
+    pub fn add_tier(
+        &mut self,
+        duration: i64,
+        reward: u64,
+    ) -> Result<()> {
+        require!(duration > 0, StakingError::InvalidStakeDuration);
+        let count = self.tier_count as usize;
+        // Re-activate a retired tier before taking a new slot
+        if let Some(tier) = self.tiers[..count].iter_mut().find(|tier| tier.duration == duration) {
+            require!(!tier.active, StakingError::RewardTierAlreadyExists);
+            tier.reward = reward;
+            tier.active = true;
+            return Ok(());
+        }
+        require!(count < MAX_REWARD_TIERS, StakingError::RewardTierTableFull);
+        self.tiers[count] = RewardTier {
+            duration,
+            reward,
+            active: true,
+        };
+        self.tier_count += 1;
+        Ok(())
+    }
+
+    pub fn update_tier(
+        &mut self,
+        duration: i64,
+        reward: u64,
+    ) -> Result<()> {
+        let count = self.tier_count as usize;
+        let tier = self.tiers[..count]
+            .iter_mut()
+            .find(|tier| tier.active && tier.duration == duration)
+            .ok_or(StakingError::InvalidRewardTier)?;
+        tier.reward = reward;
+        Ok(())
+    }
+
+    pub fn retire_tier(
+        &mut self,
+        duration: i64,
+    ) -> Result<()> {
+        let count = self.tier_count as usize;
+        let tier = self.tiers[..count]
+            .iter_mut()
+            .find(|tier| tier.active && tier.duration == duration)
+            .ok_or(StakingError::InvalidRewardTier)?;
+        tier.active = false;
+        Ok(())
+    }
+}
+
+/// Partner token paid to every staked NFT next to the pool's own reward.
+/// Streams are funded through a vault owned by the pool authority and are never
+/// removed, since staked NFTs keep their accounting at the stream's index.
+#[account]
+#[derive(Default)]
+pub struct RewardStream {
+    // 8 + 268
+    pub pool: Pubkey,               // 32
+    pub index: u8,                  // 1
+    pub mint: Pubkey,               // 32
+    pub reward_mode: u8,            // 1
+    pub reward_rate: u64,           // 8
+    pub reward_per_nft: u64,        // 8
+    pub rate_updated_time: i64,     // 8
+    pub tier_count: u64,                            // 8
+    pub tiers: [RewardTier; MAX_REWARD_TIERS],      // 17 * 10
+}
+
+impl RewardStream {
+    // Accrual is kept in u64 to fit the account, a rate that would overflow it fails instead of wrapping
+    pub fn current_reward_per_nft(&self, now: i64) -> Result<u64> {
+        let elapsed = (now - self.rate_updated_time).max(0) as u128;
+        (self.reward_rate as u128)
+            .checked_mul(elapsed)
+            .and_then(|accrued| accrued.checked_add(self.reward_per_nft as u128))
+            .and_then(|reward_per_nft| u64::try_from(reward_per_nft).ok())
+            .ok_or_else(|| error!(StakingError::RewardOverflow))
+    }
+
+    pub fn set_reward_rate(&mut self, rate: u64, now: i64) -> Result<()> {
+        self.reward_per_nft = self.current_reward_per_nft(now)?;
+        self.rate_updated_time = now;
+        self.reward_rate = rate;
+        Ok(())
+    }
+
+    pub fn find_tier(&self, duration: i64) -> Option<RewardTier> {
+        self.tiers[..self.tier_count as usize]
+            .iter()
+            .find(|tier| tier.active && tier.duration == duration)
+            .copied()
+    }
+
+    // Replace the tier table, stakes keep the reward they were staked under
+    pub fn set_tiers(&mut self, tiers: Vec<RewardTier>) -> Result<()> {
+        let mut config = RewardConfig::default();
+        for tier in tiers {
+            config.add_tier(tier.duration, tier.reward)?;
+        }
+        self.tier_count = config.tier_count;
+        self.tiers = config.tiers;
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Default, Clone, Copy)]
+pub struct AllowedCollection {
+    pub address: Pubkey,    // 32
+    pub stakeable: bool,    // 1
+    pub fusable: bool,      // 1
+    pub verify_creator: bool,       // 1
+    pub verify_collection: bool,    // 1
+}
+
+#[account]
+#[derive(Default)]
+pub struct CollectionRegistry {
+    // 8 + 368
+    pub collection_count: u64,                              // 8
+    pub collections: [AllowedCollection; MAX_COLLECTIONS],  // 36 * 10
+}
+
+impl CollectionRegistry {
+    pub fn find(
+        &self,
+        address: &Pubkey,
+    ) -> Option<&AllowedCollection> {
+        self.collections[..self.collection_count as usize]
+            .iter()
+            .find(|collection| collection.address == *address)
+    }
+
+    pub fn add_collection(
+        &mut self,
+        entry: AllowedCollection,
+    ) -> Result<()> {
+        let count = self.collection_count as usize;
+        // Already registered collections just get their flags replaced
+        if let Some(collection) = self.collections[..count].iter_mut().find(|collection| collection.address == entry.address) {
+            *collection = entry;
+            return Ok(());
+        }
+        require!(count < MAX_COLLECTIONS, StakingError::CollectionRegistryFull);
+        self.collections[count] = entry;
+        self.collection_count += 1;
+        Ok(())
+    }
+
+    pub fn remove_collection(
+        &mut self,
+        address: Pubkey,
+    ) -> Result<()> {
+        let count = self.collection_count as usize;
+        let index = self.collections[..count]
+            .iter()
+            .position(|collection| collection.address == address)
+            .ok_or(StakingError::CollectionNotRegistered)?;
+        self.collections[index] = self.collections[count - 1];
+        self.collections[count - 1] = AllowedCollection::default();
+        self.collection_count -= 1;
+        Ok(())
+    }
+}
+
+/// A staked NFT's share of one reward stream
+#[zero_copy]
+#[derive(Default, PartialEq)]
+#[repr(packed)]
+pub struct StakedStream {
+    pub reward: u64,                // 8, tier reward the NFT was staked under
+    pub paid: u64,                  // 8, tier reward paid so far or the stream's reward_per_nft checkpoint
+}
+
 /// User PDA Layout
 #[zero_copy]
 #[derive(Default, PartialEq)]
@@ -27,15 +406,113 @@ pub struct StakedData {
     pub mint: Pubkey,               // 32
     pub staked_time: i64,           // 8
     pub lock_time: i64,             // 8
-    pub duration: i64,         // 8
+    pub duration: i64,              // 8
+    pub reward: u64,                // 8
+    pub last_claimed: i64,          // 8
+    pub reward_mode: u8,            // 1
+    pub padding: [u8; 7],           // 7
+    pub reward_per_nft_paid: u128,  // 16
+    pub streams: [StakedStream; MAX_REWARD_STREAMS],    // 16 * 3
 }
 
+// Entries are cast straight out of the user pool's tail. Safe since the struct is
+// packed and only holds integers and byte arrays, so it has no padding or invalid values.
+unsafe impl bytemuck::Pod for StakedData {}
+unsafe impl bytemuck::Zeroable for StakedData {}
+
+impl StakedData {
+    // Tier reward vests linearly from staked_time until lock_time
+    pub fn accrued_reward(&self, until: i64) -> u64 {
+        self.vested(self.reward, until)
+    }
+
+    fn vested(&self, reward: u64, until: i64) -> u64 {
+        let until = until.clamp(self.staked_time, self.lock_time);
+        let total = self.lock_time - self.staked_time;
+        if total <= 0 {
+            return reward;
+        }
+        (reward as u128 * (until - self.staked_time) as u128 / total as u128) as u64
+    }
+
+    pub fn enroll_stream(&mut self, stream: &RewardStream, now: i64) -> Result<()> {
+        let index = stream.index as usize;
+        self.streams[index] = if stream.reward_mode == REWARD_MODE_CONTINUOUS {
+            StakedStream { reward: 0, paid: stream.current_reward_per_nft(now)? }
+        } else {
+            StakedStream { reward: stream.find_tier(self.duration).map_or(0, |tier| tier.reward), paid: 0 }
+        };
+        Ok(())
+    }
+
+    // Stream reward earned since the last collection, moving the checkpoint up to now
+    pub fn collect_stream(&mut self, stream: &RewardStream, now: i64) -> Result<u64> {
+        let index = stream.index as usize;
+        let staked = self.streams[index];
+        if stream.reward_mode == REWARD_MODE_CONTINUOUS {
+            let reward_per_nft = stream.current_reward_per_nft(now)?;
+            self.streams[index].paid = reward_per_nft;
+            return Ok(reward_per_nft - staked.paid);
+        }
+        let vested = self.vested(staked.reward, now);
+        self.streams[index].paid = vested;
+        Ok(vested - staked.paid)
+    }
+
+    pub fn claimable_reward(&self, now: i64, reward_per_nft: u128) -> Result<u64> {
+        if self.reward_mode == REWARD_MODE_CONTINUOUS {
+            return u64::try_from(reward_per_nft - self.reward_per_nft_paid)
+                .map_err(|_| error!(StakingError::MathOverflow));
+        }
+        Ok(self.accrued_reward(now) - self.accrued_reward(self.last_claimed))
+    }
+}
+
+/// Per-NFT record of a stake, seeded by [STAKE_RECEIPT_SEED, nft_mint]. Points at the
+/// NFT's entry in its user pool so unstaking doesn't scan, and lets indexers fetch one stake.
+#[account]
+#[derive(Default)]
+pub struct StakeReceipt {
+    // 8 + 138
+    pub owner: Pubkey,              // 32
+    pub pool: Pubkey,               // 32
+    pub nft_mint: Pubkey,           // 32
+    pub index: u64,                 // 8, entry in the owner's user pool
+    pub staked_time: i64,           // 8
+    pub lock_time: i64,             // 8
+    pub duration: i64,              // 8
+    pub reward: u64,                // 8, tier reward, 0 for continuous stakes
+    pub reward_mode: u8,            // 1
+    pub bump: u8,                   // 1
+}
+
+impl StakeReceipt {
+    pub fn issue(&mut self, owner: Pubkey, pool: Pubkey, index: usize, staked: &StakedData, bump: u8) {
+        self.owner = owner;
+        self.pool = pool;
+        self.nft_mint = staked.mint;
+        self.index = index as u64;
+        self.staked_time = staked.staked_time;
+        self.lock_time = staked.lock_time;
+        self.duration = staked.duration;
+        self.reward = staked.reward;
+        self.reward_mode = staked.reward_mode;
+        self.bump = bump;
+    }
+}
+
+/// Header of a user pool, its StakedData entries follow it in the account data
+/// and are only reachable through UserPoolMut
 #[account(zero_copy)]
 pub struct UserPool {
-    // 8 + 5640
+    // 8 + 168
     pub owner: Pubkey,                              // 32
     pub staked_count: u64,                          // 8
-    pub staking: [StakedData; STAKE_MAX_COUNT],     // 56 * 100
+    pub version: u8,                                // 1
+    pub pool: Pubkey,                               // 32
+    pub reserved: [u8; 63],                         // 63
+    pub reward_owed: u64,                           // 8, rewards the emission cap left unminted
+    pub stream_owed: [u64; MAX_REWARD_STREAMS],     // 8 * 3, stream rewards a dry vault couldn't pay yet
 }
 
 impl Default for UserPool {
@@ -44,65 +521,535 @@ impl Default for UserPool {
         UserPool {
             owner: Pubkey::default(),
             staked_count: 0,
-            staking: [
-                StakedData {
-                    ..Default::default()
-                }; STAKE_MAX_COUNT
-            ],
+            version: 0,
+            pool: Pubkey::default(),
+            reserved: [0; 63],
+            reward_owed: 0,
+            stream_owed: [0; MAX_REWARD_STREAMS],
         }
     }
 }
 
-impl UserPool {
+/// A user pool loaded with its StakedData entries. The account starts with room for
+/// STAKE_INITIAL_CAPACITY entries and is reallocated as more NFTs get staked.
+pub struct UserPoolMut<'a> {
+    header: RefMut<'a, UserPool>,
+    pub staking: RefMut<'a, [StakedData]>,
+}
+
+impl<'a> UserPoolMut<'a> {
+    pub fn load(info: &'a AccountInfo) -> Result<Self> {
+        require!(info.is_writable, ErrorCode::AccountNotMutable);
+        let data = info.try_borrow_mut_data()?;
+        // Older layouts have to go through migrate_user_pool first
+        require!(is_user_pool_len(data.len()), StakingError::UnsupportedAccountVersion);
+        require!(data[..8] == UserPool::discriminator(), ErrorCode::AccountDiscriminatorMismatch);
+
+        let (header, staking) = RefMut::map_split(data, |data| {
+            let (header, staking) = data[8..].split_at_mut(USER_POOL_HEADER_SIZE - 8);
+            (bytemuck::from_bytes_mut(header), bytemuck::cast_slice_mut(staking))
+        });
+        Ok(Self { header, staking })
+    }
+
+    /// Account length needed to stake `count` more NFTs, None while there are enough free entries
+    pub fn grown_len(&self, count: usize) -> Result<Option<usize>> {
+        let capacity = self.staking.len();
+        let needed = self.staked_count as usize + count;
+        if needed <= capacity {
+            return Ok(None);
+        }
+        // A single realloc only grows by one step
+        let capacity = (capacity + STAKE_CAPACITY_STEP).min(STAKE_MAX_COUNT);
+        require!(needed <= capacity, StakingError::StakeLimitReached);
+        Ok(Some(USER_POOL_HEADER_SIZE + capacity * STAKED_DATA_SIZE))
+    }
+
     pub fn add_nft(
         &mut self,
         nft_pubkey: Pubkey,
-        duration: i64,
+        tier: RewardTier,
         now: i64,
-    ) {
+        global_pool: &mut GlobalPool,
+        streams: &[&RewardStream],
+    ) -> Result<usize> {
         let idx = self.staked_count as usize;
+        require!(idx < self.staking.len(), StakingError::StakeLimitReached);
+        let reward_mode = global_pool.reward_mode;
         self.staking[idx].mint = nft_pubkey;
         self.staking[idx].staked_time = now;
-        self.staking[idx].lock_time = now + duration * DAY;
-        self.staking[idx].duration = duration;
+        self.staking[idx].lock_time = now + tier.duration * DAY;
+        self.staking[idx].duration = tier.duration;
+        self.staking[idx].last_claimed = now;
+        self.staking[idx].reward_mode = reward_mode;
+        // Keep the payout the NFT was staked under, continuous stakes only use the tier lock
+        if reward_mode == REWARD_MODE_CONTINUOUS {
+            self.staking[idx].reward = 0;
+            self.staking[idx].reward_per_nft_paid = global_pool.current_reward_per_nft(now);
+        } else {
+            self.staking[idx].reward = tier.reward;
+            self.staking[idx].reward_per_nft_paid = 0;
+        }
+        global_pool.commit_stake(&self.staking[idx]);
+        self.staking[idx].streams = Default::default();
+        for stream in streams {
+            self.staking[idx].enroll_stream(stream, now)?;
+        }
        
         self.staked_count += 1;
+        Ok(idx)
     }
     
+    /// Remove the entry a receipt points at. The last entry moves into its place,
+    /// `last_receipt` has to be that entry's receipt so it keeps pointing at it.
     pub fn remove_nft(
         &mut self,
-        nft_pubkey: Pubkey,
+        receipt: &StakeReceipt,
+        last_receipt: &mut StakeReceipt,
         now: i64,
-    ) -> Result<u64> {
-        let mut withdrawn: u8 = 0;
-        let mut index: usize = 0;
-        let mut reward: u64 = 0;
-        // Find NFT in pool
-        for i in 0..self.staked_count {
-            let idx = i as usize;
-            if self.staking[idx].mint.eq(&nft_pubkey) {
-                if self.staking[idx].lock_time <= now {
-                    match self.staking[index].duration {
-                        1 => reward = 90 * AMMO_DECIMAL,
-                        5 => reward = 540 * AMMO_DECIMAL,
-                        15 => reward = 1620 * AMMO_DECIMAL,
-                        30 => reward = 3150 * AMMO_DECIMAL,
-                        _ => reward = 0,
-                    }
-                }
-                index = idx;
-                withdrawn = 1;
-                break;
+        global_pool: &mut GlobalPool,
+        streams: &[&RewardStream],
+    ) -> Result<(u64, u64, [u64; MAX_REWARD_STREAMS])> {
+        let index = self.receipt_entry(receipt, last_receipt)?;
+        let mut penalty: u64 = 0;
+        let reward_per_nft = global_pool.current_reward_per_nft(now);
+        let mut reward = self.staking[index].claimable_reward(now, reward_per_nft)?;
+        let mut stream_rewards = [0u64; MAX_REWARD_STREAMS];
+        for stream in streams {
+            stream_rewards[stream.index as usize] = self.staking[index].collect_stream(stream, now)?;
+        }
+        if self.staking[index].lock_time > now {
+            let percent = global_pool.early_unstake_penalty().ok_or(StakingError::InvalidWithdrawTime)?;
+            penalty = (reward as u128 * percent as u128 / 100) as u64;
+            reward -= penalty;
+            // Stream penalties just stay in their vaults
+            for stream_reward in stream_rewards.iter_mut() {
+                *stream_reward -= (*stream_reward as u128 * percent as u128 / 100) as u64;
             }
         }
-        require!(withdrawn == 1, StakingError::InvalidNFTAddress);
-       
-        // Remove NFT from pool
+        global_pool.release_stake(&self.staking[index]);
+        self.remove_at(index, last_receipt);
+        Ok((reward, penalty, stream_rewards))
+    }
+
+    // Give the NFT back without any reward, ignoring lock_time
+    pub fn emergency_remove_nft(
+        &mut self,
+        receipt: &StakeReceipt,
+        last_receipt: &mut StakeReceipt,
+        global_pool: &mut GlobalPool,
+    ) -> Result<()> {
+        let index = self.receipt_entry(receipt, last_receipt)?;
+        global_pool.release_stake(&self.staking[index]);
+        self.remove_at(index, last_receipt);
+        Ok(())
+    }
+
+    // Entry the receipt points at, checking last_receipt belongs to the last entry
+    fn receipt_entry(&self, receipt: &StakeReceipt, last_receipt: &StakeReceipt) -> Result<usize> {
+        let index = receipt.index as usize;
+        let count = self.staked_count as usize;
+        require!(
+            index < count
+                && self.staking[index].mint == receipt.nft_mint
+                && self.staking[count - 1].mint == last_receipt.nft_mint,
+            StakingError::InvalidStakeReceipt
+        );
+        Ok(index)
+    }
+
+    // Stakes made before receipts existed are only found by scanning
+    pub fn find_nft(&self, nft_pubkey: &Pubkey) -> Result<usize> {
+        let index = self.staking[..self.staked_count as usize]
+            .iter()
+            .position(|staked| staked.mint.eq(nft_pubkey))
+            .ok_or(StakingError::InvalidNFTAddress)?;
+        Ok(index)
+    }
+
+    fn remove_at(&mut self, index: usize, last_receipt: &mut StakeReceipt) {
         let last_idx: usize = (self.staked_count - 1) as usize;
         if index != last_idx {
             self.staking[index] = self.staking[last_idx];
+            last_receipt.index = index as u64;
         }
         self.staked_count -= 1;
+    }
+
+    // Rewards of unlocked stakes, including what the emission cap left unminted before
+    pub fn claim_rewards(
+        &mut self,
+        now: i64,
+        global_pool: &mut GlobalPool,
+    ) -> Result<u64> {
+        let mut reward: u64 = self.reward_owed;
+        global_pool.reward_owed -= self.reward_owed;
+        self.reward_owed = 0;
+        let reward_per_nft = global_pool.current_reward_per_nft(now);
+        for i in 0..self.staked_count {
+            let idx = i as usize;
+            if global_pool.defers_claim(&self.staking[idx], now) {
+                continue;
+            }
+            let claimable = self.staking[idx].claimable_reward(now, reward_per_nft)?;
+            global_pool.settle_claim(&self.staking[idx], claimable);
+            reward += claimable;
+            self.staking[idx].last_claimed = now;
+            if self.staking[idx].reward_mode == REWARD_MODE_CONTINUOUS {
+                self.staking[idx].reward_per_nft_paid = reward_per_nft;
+            }
+        }
         Ok(reward)
     }
-}
\ No newline at end of file
+
+    // Keep a reward the emission cap left unminted until the next claim
+    pub fn owe_reward(&mut self, amount: u64, global_pool: &mut GlobalPool) {
+        self.reward_owed += amount;
+        global_pool.reward_owed += amount;
+    }
+
+    // Everything a stream owes this user, including what its vault couldn't pay before
+    pub fn claim_stream(
+        &mut self,
+        stream: &RewardStream,
+        now: i64,
+        global_pool: &GlobalPool,
+    ) -> Result<u64> {
+        let index = stream.index as usize;
+        let mut reward = self.stream_owed[index];
+        self.stream_owed[index] = 0;
+        for i in 0..self.staked_count as usize {
+            if global_pool.defers_claim(&self.staking[i], now) {
+                continue;
+            }
+            reward += self.staking[i].collect_stream(stream, now)?;
+        }
+        Ok(reward)
+    }
+}
+
+impl Deref for UserPoolMut<'_> {
+    type Target = UserPool;
+
+    fn deref(&self) -> &UserPool {
+        &self.header
+    }
+}
+
+impl DerefMut for UserPoolMut<'_> {
+    fn deref_mut(&mut self) -> &mut UserPool {
+        &mut self.header
+    }
+}
+
+/// Whether `len` is a user pool of the current layout with a whole number of entries
+pub fn is_user_pool_len(len: usize) -> bool {
+    let entries = len.saturating_sub(USER_POOL_HEADER_SIZE) / STAKED_DATA_SIZE;
+    len >= USER_POOL_SIZE && len == USER_POOL_HEADER_SIZE + entries * STAKED_DATA_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_len(capacity: usize) -> usize {
+        USER_POOL_HEADER_SIZE + capacity * STAKED_DATA_SIZE
+    }
+
+    fn user_pool_bytes(capacity: usize) -> Vec<u8> {
+        let mut data = vec![0u8; data_len(capacity)];
+        data[..8].copy_from_slice(&UserPool::discriminator());
+        data
+    }
+
+    // An empty user pool with the initial capacity. Its account is leaked so tests
+    // don't have to keep the AccountInfo and its buffers in scope.
+    fn new_user_pool() -> UserPoolMut<'static> {
+        let key = Box::leak(Box::new(Pubkey::new_unique()));
+        let lamports = Box::leak(Box::new(0));
+        let data = Box::leak(user_pool_bytes(STAKE_INITIAL_CAPACITY).into_boxed_slice());
+        let info = Box::leak(Box::new(AccountInfo::new(key, false, true, lamports, data, &crate::ID, false, 0)));
+        UserPoolMut::load(info).unwrap()
+    }
+
+    #[test]
+    fn layout_sizes_match_constants() {
+        assert_eq!(8 + std::mem::size_of::<UserPool>(), USER_POOL_HEADER_SIZE);
+        assert_eq!(std::mem::size_of::<StakedData>(), STAKED_DATA_SIZE);
+        assert!(is_user_pool_len(USER_POOL_SIZE));
+        assert!(is_user_pool_len(USER_POOL_SIZE + STAKED_DATA_SIZE));
+        assert!(!is_user_pool_len(USER_POOL_SIZE - STAKED_DATA_SIZE));
+        assert!(!is_user_pool_len(USER_POOL_SIZE + 1));
+    }
+
+    #[test]
+    fn user_pool_grows_until_the_hard_limit() {
+        let tier = RewardTier { duration: 1, reward: 10, active: true };
+        let mut global_pool = GlobalPool::default();
+        let mut capacity = STAKE_INITIAL_CAPACITY;
+        let mut data = user_pool_bytes(capacity);
+        loop {
+            let (key, mut lamports) = (Pubkey::new_unique(), 0);
+            let info = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &crate::ID, false, 0);
+            let mut user_pool = UserPoolMut::load(&info).unwrap();
+            assert_eq!(user_pool.staking.len(), capacity);
+            while (user_pool.staked_count as usize) < capacity {
+                assert_eq!(user_pool.grown_len(1).unwrap(), None);
+                user_pool.add_nft(Pubkey::new_unique(), tier, 0, &mut global_pool, &[]).unwrap();
+            }
+            assert!(user_pool.add_nft(Pubkey::new_unique(), tier, 0, &mut global_pool, &[]).is_err());
+
+            if capacity == STAKE_MAX_COUNT {
+                assert!(user_pool.grown_len(1).is_err());
+                break;
+            }
+            let new_len = user_pool.grown_len(1).unwrap().unwrap();
+            assert!(new_len - data_len(capacity) <= 10240);
+            drop(user_pool);
+            drop(info);
+            data.resize(new_len, 0);
+            capacity = (new_len - USER_POOL_HEADER_SIZE) / STAKED_DATA_SIZE;
+        }
+        assert_eq!(global_pool.reward_liability, 10 * STAKE_MAX_COUNT as u64);
+    }
+
+    #[test]
+    fn batches_grow_the_user_pool_by_one_step() {
+        let tier = RewardTier { duration: 1, reward: 10, active: true };
+        let mut global_pool = GlobalPool::default();
+        let mut user_pool = new_user_pool();
+        for _ in 0..STAKE_INITIAL_CAPACITY - 2 {
+            user_pool.add_nft(Pubkey::new_unique(), tier, 0, &mut global_pool, &[]).unwrap();
+        }
+        assert_eq!(user_pool.grown_len(2).unwrap(), None);
+        let grown = data_len(STAKE_INITIAL_CAPACITY + STAKE_CAPACITY_STEP);
+        assert_eq!(user_pool.grown_len(3).unwrap(), Some(grown));
+        assert_eq!(user_pool.grown_len(STAKE_CAPACITY_STEP + 2).unwrap(), Some(grown));
+        assert!(user_pool.grown_len(STAKE_CAPACITY_STEP + 3).is_err());
+    }
+
+    #[test]
+    fn receipts_follow_entries_moved_by_removal() {
+        let tier = RewardTier { duration: 1, reward: 10, active: true };
+        let mut global_pool = GlobalPool::default();
+        let mut user_pool = new_user_pool();
+        let (owner, pool) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut receipts: Vec<StakeReceipt> = (0..3)
+            .map(|_| {
+                let index = user_pool.add_nft(Pubkey::new_unique(), tier, 0, &mut global_pool, &[]).unwrap();
+                let mut receipt = StakeReceipt::default();
+                receipt.issue(owner, pool, index, &user_pool.staking[index], 255);
+                receipt
+            })
+            .collect();
+
+        // Only the last entry's receipt can follow it
+        let (first, rest) = receipts.split_at_mut(1);
+        assert!(user_pool.emergency_remove_nft(&first[0], &mut rest[0], &mut global_pool).is_err());
+        user_pool.emergency_remove_nft(&first[0], &mut rest[1], &mut global_pool).unwrap();
+        assert_eq!({ user_pool.staked_count }, 2);
+        assert_eq!(rest[1].index, 0);
+        assert_eq!({ user_pool.staking[0].mint }, rest[1].nft_mint);
+        assert_eq!({ user_pool.staking[1].mint }, rest[0].nft_mint);
+
+        // A closed receipt no longer matches its old entry
+        assert!(user_pool.emergency_remove_nft(&first[0], &mut rest[0], &mut global_pool).is_err());
+
+        // Removing the last entry passes its own receipt as the last one
+        let mut last = rest[0].clone();
+        user_pool.emergency_remove_nft(&rest[0], &mut last, &mut global_pool).unwrap();
+        assert_eq!(last.index, 1);
+        assert_eq!({ user_pool.staked_count }, 1);
+        assert_eq!(global_pool.reward_liability, 10);
+    }
+
+    // Stake `count` NFTs at time 0, returning their receipts
+    fn stake_all(user_pool: &mut UserPoolMut, global_pool: &mut GlobalPool, tier: RewardTier, count: usize) -> Vec<StakeReceipt> {
+        (0..count)
+            .map(|_| {
+                let index = user_pool.add_nft(Pubkey::new_unique(), tier, 0, global_pool, &[]).unwrap();
+                let mut receipt = StakeReceipt::default();
+                receipt.issue(Pubkey::default(), Pubkey::default(), index, &user_pool.staking[index], 255);
+                receipt
+            })
+            .collect()
+    }
+
+    #[test]
+    fn liability_follows_stakes_claims_and_unstakes() {
+        let tier = RewardTier { duration: 10, reward: 1000, active: true };
+        let mut global_pool = GlobalPool::default();
+        let mut user_pool = new_user_pool();
+        let mut receipts = stake_all(&mut user_pool, &mut global_pool, tier, 2);
+        assert_eq!(global_pool.reward_liability, 2000);
+        assert!(global_pool.is_solvent(2000, 0));
+        assert!(!global_pool.is_solvent(1999, 0));
+
+        // Partial claim, a fifth of the lock has passed
+        assert_eq!(user_pool.claim_rewards(2 * DAY, &mut global_pool).unwrap(), 400);
+        assert_eq!(global_pool.reward_liability, 1600);
+        assert!(!global_pool.is_solvent(1599, 2 * DAY));
+
+        // Early unstake, half of what accrued since the claim is the penalty
+        global_pool.early_unstake_policy = EARLY_UNSTAKE_PENALTY;
+        global_pool.early_unstake_penalty = 50;
+        let mut last = receipts[1].clone();
+        let (reward, penalty, _) = user_pool.remove_nft(&receipts[0], &mut last, 5 * DAY, &mut global_pool, &[]).unwrap();
+        receipts[1] = last;
+        assert_eq!((reward, penalty), (150, 150));
+        assert_eq!(global_pool.reward_liability, 800);
+
+        // Full unstake once the lock has passed
+        let mut last = receipts[1].clone();
+        let (reward, penalty, _) = user_pool.remove_nft(&receipts[1], &mut last, 10 * DAY, &mut global_pool, &[]).unwrap();
+        assert_eq!((reward, penalty), (800, 0));
+        assert_eq!(global_pool.reward_liability, 0);
+        assert!(global_pool.is_solvent(0, 10 * DAY));
+    }
+
+    #[test]
+    fn emission_cap_defers_rewards_it_cannot_mint() {
+        let tier = RewardTier { duration: 10, reward: 1000, active: true };
+        let mut global_pool = GlobalPool { reward_funding: REWARD_FUNDING_MINT, emission_cap: 600, ..Default::default() };
+        let mut user_pool = new_user_pool();
+        let receipts = stake_all(&mut user_pool, &mut global_pool, tier, 1);
+
+        // The unstake still goes through, what doesn't fit under the cap is owed
+        let mut last = receipts[0].clone();
+        let (reward, _, _) = user_pool.remove_nft(&receipts[0], &mut last, 10 * DAY, &mut global_pool, &[]).unwrap();
+        let mintable = global_pool.record_emission(reward);
+        user_pool.owe_reward(reward - mintable, &mut global_pool);
+        assert_eq!((mintable, { user_pool.reward_owed }), (600, 400));
+        assert_eq!(global_pool.record_emission(1), 0);
+        assert_eq!(global_pool.emitted, 600);
+
+        // What is owed stays a liability, nothing else can be staked against the cap meanwhile
+        assert_eq!((global_pool.reward_liability, global_pool.outstanding_rewards(10 * DAY)), (0, 400));
+        assert!(!global_pool.is_solvent(0, 10 * DAY));
+
+        // Once the cap is raised the next claim pays it
+        global_pool.emission_cap = 1000;
+        assert!(global_pool.is_solvent(0, 10 * DAY));
+        assert_eq!(user_pool.claim_rewards(11 * DAY, &mut global_pool).unwrap(), 400);
+        assert_eq!(({ user_pool.reward_owed }, global_pool.reward_owed), (0, 0));
+        assert_eq!(global_pool.record_emission(400), 400);
+        assert_eq!(user_pool.claim_rewards(12 * DAY, &mut global_pool).unwrap(), 0);
+    }
+
+    fn tier_stream(tier: RewardTier) -> RewardStream {
+        let mut stream = RewardStream { reward_mode: REWARD_MODE_TIER, ..Default::default() };
+        stream.set_tiers(vec![tier]).unwrap();
+        stream
+    }
+
+    // Early unstake halfway through the lock, with a 200 reward and a 100 stream reward
+    fn unstake_early(global_pool: &mut GlobalPool) -> Result<(u64, u64, [u64; MAX_REWARD_STREAMS])> {
+        let tier = RewardTier { duration: 10, reward: 200, active: true };
+        let stream = tier_stream(RewardTier { reward: 100, ..tier });
+        let mut user_pool = new_user_pool();
+        let index = user_pool.add_nft(Pubkey::new_unique(), tier, 0, global_pool, &[&stream]).unwrap();
+        let mut receipt = StakeReceipt::default();
+        receipt.issue(Pubkey::default(), Pubkey::default(), index, &user_pool.staking[index], 255);
+        let mut last = receipt.clone();
+        user_pool.remove_nft(&receipt, &mut last, 5 * DAY, global_pool, &[&stream])
+    }
+
+    #[test]
+    fn early_unstake_penalty_percentages() {
+        let mut global_pool = GlobalPool { early_unstake_policy: EARLY_UNSTAKE_PENALTY, ..Default::default() };
+        for (percent, reward, penalty, stream_reward) in [(0, 100, 0, 50), (25, 75, 25, 38), (100, 0, 100, 0)] {
+            global_pool.early_unstake_penalty = percent;
+            let (paid, burned, streams) = unstake_early(&mut global_pool).unwrap();
+            assert_eq!((paid, burned, streams[0]), (reward, penalty, stream_reward));
+            assert_eq!(global_pool.reward_liability, 0);
+        }
+    }
+
+    #[test]
+    fn reject_policy_refuses_early_unstakes() {
+        let mut global_pool = GlobalPool { early_unstake_policy: EARLY_UNSTAKE_REJECT, ..Default::default() };
+        let err = unstake_early(&mut global_pool).unwrap_err();
+        assert_eq!(ProgramError::from(err), ProgramError::from(error!(StakingError::InvalidWithdrawTime)));
+    }
+
+    #[test]
+    fn penalty_policy_defers_claims_until_unstake() {
+        let tier = RewardTier { duration: 10, reward: 1000, active: true };
+        let stream = tier_stream(RewardTier { reward: 100, ..tier });
+        let mut global_pool = GlobalPool {
+            early_unstake_policy: EARLY_UNSTAKE_PENALTY,
+            early_unstake_penalty: 50,
+            ..Default::default()
+        };
+        let mut user_pool = new_user_pool();
+        let index = user_pool.add_nft(Pubkey::new_unique(), tier, 0, &mut global_pool, &[&stream]).unwrap();
+        let mut receipt = StakeReceipt::default();
+        receipt.issue(Pubkey::default(), Pubkey::default(), index, &user_pool.staking[index], 255);
+
+        // Claiming while locked pays nothing, so the penalty still covers everything accrued
+        assert_eq!(user_pool.claim_rewards(4 * DAY, &mut global_pool).unwrap(), 0);
+        assert_eq!(user_pool.claim_stream(&stream, 4 * DAY, &global_pool).unwrap(), 0);
+        let mut last = receipt.clone();
+        let (reward, penalty, streams) = user_pool.remove_nft(&receipt, &mut last, 5 * DAY, &mut global_pool, &[&stream]).unwrap();
+        assert_eq!((reward, penalty, streams[0]), (250, 250, 25));
+    }
+
+    #[test]
+    fn continuous_claims_fail_past_u64() {
+        let staked = StakedData { reward_mode: REWARD_MODE_CONTINUOUS, reward_per_nft_paid: 1, ..Default::default() };
+        assert_eq!(staked.claimable_reward(0, u64::MAX as u128 + 1).unwrap(), u64::MAX);
+        assert!(staked.claimable_reward(0, u64::MAX as u128 + 2).is_err());
+    }
+
+    #[test]
+    fn stream_accrual_fails_instead_of_overflowing() {
+        let mut stream = RewardStream { reward_mode: REWARD_MODE_CONTINUOUS, reward_rate: u64::MAX / 2, ..Default::default() };
+        assert_eq!(stream.current_reward_per_nft(2).unwrap(), u64::MAX - 1);
+        assert!(stream.current_reward_per_nft(3).is_err());
+
+        // A stake can't be enrolled or collected past the overflow, and the rate can't be checkpointed
+        let mut staked = StakedData { reward_mode: REWARD_MODE_CONTINUOUS, ..Default::default() };
+        staked.enroll_stream(&stream, 1).unwrap();
+        assert_eq!(staked.collect_stream(&stream, 2).unwrap(), u64::MAX / 2);
+        assert!(staked.collect_stream(&stream, 3).is_err());
+        assert!(stream.set_reward_rate(0, 3).is_err());
+        assert_eq!(stream.reward_rate, u64::MAX / 2);
+    }
+
+    #[test]
+    fn loosening_the_withdraw_timelock_waits_out_the_current_delay() {
+        let mut global_pool = GlobalPool { withdraw_delay: MIN_WITHDRAW_DELAY, ..Default::default() };
+        assert!(global_pool.set_withdraw_timelock(100, 0, 0).is_err());
+        assert!(global_pool.apply_withdraw_timelock(0).is_err());
+
+        // Raising the threshold only applies after the delay
+        assert_eq!(global_pool.set_withdraw_timelock(u64::MAX, DAY, 10).unwrap(), 10 + DAY);
+        assert_eq!(global_pool.withdraw_threshold, 0);
+        assert!(global_pool.apply_withdraw_timelock(DAY).is_err());
+        global_pool.apply_withdraw_timelock(10 + DAY).unwrap();
+        assert_eq!(global_pool.withdraw_threshold, u64::MAX);
+
+        // Tightening applies right away and drops a queued change
+        global_pool.set_withdraw_timelock(u64::MAX, 2 * DAY, 0).unwrap();
+        assert_eq!(global_pool.withdraw_delay, 2 * DAY);
+        assert_eq!(global_pool.set_withdraw_timelock(u64::MAX, DAY, 0).unwrap(), 2 * DAY);
+        global_pool.set_withdraw_timelock(100, 3 * DAY, 0).unwrap();
+        assert_eq!((global_pool.withdraw_threshold, global_pool.withdraw_delay), (100, 3 * DAY));
+        assert!(global_pool.apply_withdraw_timelock(3 * DAY).is_err());
+    }
+
+    #[test]
+    fn rejects_accounts_that_are_not_current_user_pools() {
+        let (key, mut lamports) = (Pubkey::new_unique(), 0);
+        let mut data = user_pool_bytes(STAKE_INITIAL_CAPACITY);
+        data.pop();
+        let info = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &crate::ID, false, 0);
+        assert!(UserPoolMut::load(&info).is_err());
+
+        let (key, mut lamports) = (Pubkey::new_unique(), 0);
+        let mut data = user_pool_bytes(STAKE_INITIAL_CAPACITY);
+        data[..8].copy_from_slice(&GlobalPool::discriminator());
+        let info = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &crate::ID, false, 0);
+        assert!(UserPoolMut::load(&info).is_err());
+    }
+}
@@ -1,12 +1,61 @@
+use solana_program::entrypoint::MAX_PERMITTED_DATA_INCREASE;
+
+// Pool authorities are seeded with [GLOBAL_AUTHORITY_SEED, pool id], see pool_id_seed
 pub const GLOBAL_AUTHORITY_SEED: &str = "global-authority";
 pub const VAULT_SEED: &str = "vault-seed";
+pub const REWARD_CONFIG_SEED: &str = "reward-config";
+pub const COLLECTION_REGISTRY_SEED: &str = "collection-registry";
+pub const PENDING_WITHDRAWAL_SEED: &str = "pending-withdrawal";
+pub const REWARD_STREAM_SEED: &str = "reward-stream";
+pub const STAKE_RECEIPT_SEED: &str = "receipt";
+pub const USER_POOL_SEED: &str = "user-pool";
 
-
-pub const COLLECTION_ADDRESS: &str = "9VYHEsB5k9CA1gbUbxJ7CsnxhV9GgegjqiCCSy1APNF1";
-pub const COLLECTION_ADDRESS_2D: &str = "BGSpWrrB6FtnSPZ8PhS7dXiUVLeQyCqxRz3t8cGK2NxH";
-
-pub const REWARD_TOKEN_MINT_PUBKEY: &str = "H1X2quMc8ZesCE4afDQhWAmFssENprmmz9a6SXvHWPoN";
+// Account layout versions, accounts created before versioning are version 0
+pub const GLOBAL_POOL_VERSION: u8 = 1;
+pub const USER_POOL_VERSION: u8 = 2;
+pub const GLOBAL_POOL_SIZE: usize = 8 + 456;
+// User pools are a header followed by their StakedData entries, see UserPoolMut
+pub const USER_POOL_HEADER_SIZE: usize = 8 + 168;
+pub const STAKED_DATA_SIZE: usize = 144;
+pub const USER_POOL_SIZE: usize = USER_POOL_HEADER_SIZE + STAKED_DATA_SIZE * STAKE_INITIAL_CAPACITY;
+pub const STAKE_RECEIPT_SIZE: usize = 8 + 138;
 
 pub const AMMO_DECIMAL: u64 = 1_000_000_000;   // AMMO Token Decimal
 pub const DAY: i64 = 86400;
-pub const STAKE_MAX_COUNT: usize = 100;
\ No newline at end of file
+pub const MIN_WITHDRAW_DELAY: i64 = DAY;        // Shortest timelock on treasury withdrawals above the threshold
+// Entries a new user pool has room for, as many as a PDA can be created with
+pub const STAKE_INITIAL_CAPACITY: usize = (MAX_PERMITTED_DATA_INCREASE - USER_POOL_HEADER_SIZE) / STAKED_DATA_SIZE;
+pub const STAKE_CAPACITY_STEP: usize = 50;      // Entries added per realloc, within the 10KiB realloc limit
+pub const STAKE_MAX_COUNT: usize = 500;         // Hard limit, keeps claiming every entry within the compute budget
+pub const MAX_REWARD_TIERS: usize = 10;
+pub const MAX_REWARD_STREAMS: usize = 3;    // Partner streams on top of the pool's own reward
+pub const STREAM_PAYOUT_ACCOUNTS: usize = 5;    // remaining_accounts per stream paid out
+pub const STAKE_BATCH_ACCOUNTS: usize = 5;      // remaining_accounts per NFT of stake_nfts_batch
+pub const WITHDRAW_BATCH_ACCOUNTS: usize = 5;   // remaining_accounts per NFT of withdraw_nfts_batch
+
+// Reward modes
+pub const REWARD_MODE_TIER: u8 = 0;         // Lump-sum tier reward over the lock period
+pub const REWARD_MODE_CONTINUOUS: u8 = 1;   // Global per-second rate per staked NFT
+
+// Reward funding, where paid out rewards come from
+pub const REWARD_FUNDING_VAULT: u8 = 0;     // Transferred from the pre-funded reward vault
+pub const REWARD_FUNDING_MINT: u8 = 1;      // Minted by the pool authority up to the emission cap
+
+// Pause flags, one bit per instruction group
+pub const PAUSE_STAKING: u8 = 1 << 0;       // user pool setup, migration and closing, staking, issue_stake_receipt
+pub const PAUSE_UNSTAKING: u8 = 1 << 1;     // withdraw_nft_from_pool, withdraw_nfts_batch, claim_rewards
+pub const PAUSE_VAULT: u8 = 1 << 2;         // user vault setup, closing, deposits and withdrawals, reward vault withdrawals
+pub const PAUSE_FUSION: u8 = 1 << 3;        // fusion
+pub const PAUSE_ALL: u8 = PAUSE_STAKING | PAUSE_UNSTAKING | PAUSE_VAULT | PAUSE_FUSION;
+
+// Roles the super admin can delegate
+pub const ROLE_TREASURER: u8 = 0;           // withdraw_token
+pub const ROLE_OPERATOR: u8 = 1;            // Reward tiers, reward mode/rate, reward streams, collections
+pub const ROLE_PAUSER: u8 = 2;              // Pause flags and emergency withdraw
+pub const ROLE_FUSION_SIGNER: u8 = 3;       // Update authority signing fusion
+pub const ROLE_COUNT: usize = 4;
+
+// Early unstake policies
+pub const EARLY_UNSTAKE_REJECT: u8 = 0;     // Unstaking before lock_time fails
+pub const EARLY_UNSTAKE_PENALTY: u8 = 1;    // Pro-rata reward minus the penalty percentage
+pub const MAX_COLLECTIONS: usize = 10;
@@ -4,25 +4,107 @@ use anchor_lang::prelude::*;
 pub enum StakingError {
     #[msg("Invalid Super Owner")]
     InvalidSuperOwner,
+    #[msg("Initializer Is Not The Program Upgrade Authority")]
+    InvalidUpgradeAuthority,
+    #[msg("Invalid Pending Super Owner")]
+    InvalidPendingAdmin,
+    #[msg("Signer Doesn't Hold The Required Role")]
+    MissingRole,
+    #[msg("Invalid Role")]
+    InvalidRole,
     #[msg("Invalid Global Pool Address")]
     InvalidGlobalPool,
     #[msg("Invalid User Pool Owner Address")]
     InvalidUserPool,
+    #[msg("Account Layout Can't Be Migrated")]
+    UnsupportedAccountVersion,
+    #[msg("User Pool Is Already Initialized")]
+    UserPoolExists,
+    #[msg("User Pool Still Holds Staked NFTs Or Owed Rewards")]
+    UserPoolNotEmpty,
+    #[msg("User Vault Still Holds Tokens")]
+    UserVaultNotEmpty,
 
     #[msg("Invalid Withdraw Time")]
     InvalidWithdrawTime,
     #[msg("Not Found Staked Mint")]
     InvalidNFTAddress,
+    #[msg("User Pool Can't Hold More Staked NFTs")]
+    StakeLimitReached,
+    #[msg("Stake Receipt Doesn't Match The Staked Entry")]
+    InvalidStakeReceipt,
+    #[msg("Invalid Batch NFT Accounts")]
+    InvalidBatchAccounts,
 
     #[msg("Insufficient Reward Token Balance")]
     InsufficientRewardVault,
     #[msg("Insufficient Account Token Balance")]
     InsufficientAccountVault,
+    #[msg("Account Token Balance Overflow")]
+    AccountVaultOverflow,
+    #[msg("Not An SPL Token Or Token-2022 Account")]
+    InvalidTokenAccount,
+    #[msg("Not An SPL Token Or Token-2022 Mint")]
+    InvalidTokenMint,
+    #[msg("Token Program Doesn't Own The Reward Mint")]
+    InvalidTokenProgram,
 
     #[msg("Invalid Metadata Address")]
     InvalidMetadata,
-    #[msg("Can't Parse The NFT's Creators")]
+    #[msg("Can't Parse The NFT's Creators Or Collection")]
     MetadataCreatorParseError,
     #[msg("Unknown Collection Or The Collection Is Not Allowed")]
     UnkownOrNotAllowedNFTCollection,
+
+    #[msg("Reward Tier Not Found Or Retired")]
+    InvalidRewardTier,
+    #[msg("Reward Tier Already Exists")]
+    RewardTierAlreadyExists,
+    #[msg("Reward Tier Table Is Full")]
+    RewardTierTableFull,
+    #[msg("No Reward Tier For This Stake Duration")]
+    InvalidStakeDuration,
+
+    #[msg("Collection Registry Is Full")]
+    CollectionRegistryFull,
+    #[msg("Collection Is Not Registered")]
+    CollectionNotRegistered,
+    #[msg("Collection Must Be Verified By Creator Or Collection")]
+    InvalidCollectionGate,
+
+    #[msg("Invalid Reward Mode")]
+    InvalidRewardMode,
+    #[msg("Invalid Early Unstake Policy")]
+    InvalidEarlyUnstakePolicy,
+
+    #[msg("Reward Stream Limit Reached")]
+    RewardStreamLimitReached,
+    #[msg("Every Reward Stream Must Be Passed In Order")]
+    InvalidRewardStreams,
+    #[msg("Reward Stream Accrual Overflow")]
+    RewardOverflow,
+    #[msg("Math Overflow")]
+    MathOverflow,
+
+    #[msg("Reward Vault Can't Cover Outstanding Rewards")]
+    RewardVaultInsolvent,
+    #[msg("Withdrawal Above Threshold Must Be Queued")]
+    WithdrawalRequiresTimelock,
+    #[msg("Withdrawal Timelock Has Not Passed")]
+    WithdrawalTimelocked,
+    #[msg("Withdraw Delay Is Below The Minimum")]
+    InvalidWithdrawDelay,
+    #[msg("No Withdraw Timelock Change Is Queued")]
+    NoPendingTimelockChange,
+    #[msg("Invalid Reward Funding")]
+    InvalidRewardFunding,
+    #[msg("Pool Authority Is Not The Reward Mint Authority")]
+    InvalidMintAuthority,
+
+    #[msg("This Instruction Is Paused")]
+    ProgramPaused,
+    #[msg("Invalid Pause Flags")]
+    InvalidPauseFlags,
+    #[msg("Emergency Withdraw Is Not Enabled")]
+    EmergencyNotEnabled,
 }
\ No newline at end of file
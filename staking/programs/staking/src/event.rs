@@ -0,0 +1,111 @@
+use anchor_lang::prelude::*;
+
+#[event]
+pub struct PoolCreated {
+    pub pool: Pubkey,
+    pub pool_id: u64,
+    pub admin: Pubkey,
+    pub reward_mint: Pubkey,
+}
+
+#[event]
+pub struct WithdrawTimelockUpdated {
+    pub pool: Pubkey,
+    pub admin: Pubkey,
+    pub threshold: u64,
+    pub delay: i64,
+    pub effective_after: i64,
+}
+
+#[event]
+pub struct RewardFundingUpdated {
+    pub pool: Pubkey,
+    pub admin: Pubkey,
+    pub reward_funding: u8,
+    pub emission_cap: u64,
+}
+
+#[event]
+pub struct RewardStreamAdded {
+    pub pool: Pubkey,
+    pub admin: Pubkey,
+    pub stream: Pubkey,
+    pub index: u8,
+    pub mint: Pubkey,
+}
+
+#[event]
+pub struct PauseUpdated {
+    pub pool: Pubkey,
+    pub admin: Pubkey,
+    pub previous: u8,
+    pub paused: u8,
+}
+
+#[event]
+pub struct AdminProposed {
+    pub pool: Pubkey,
+    pub admin: Pubkey,
+    pub pending_admin: Pubkey,
+}
+
+#[event]
+pub struct AdminTransferCancelled {
+    pub pool: Pubkey,
+    pub admin: Pubkey,
+    pub pending_admin: Pubkey,
+}
+
+#[event]
+pub struct AdminTransferred {
+    pub pool: Pubkey,
+    pub previous_admin: Pubkey,
+    pub admin: Pubkey,
+}
+
+#[event]
+pub struct RoleUpdated {
+    pub pool: Pubkey,
+    pub admin: Pubkey,
+    pub role: u8,
+    pub account: Pubkey,
+}
+
+#[event]
+pub struct EmergencyUpdated {
+    pub pool: Pubkey,
+    pub admin: Pubkey,
+    pub emergency: bool,
+}
+
+#[event]
+pub struct EmergencyWithdrawn {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub mint: Pubkey,
+}
+
+#[event]
+pub struct WithdrawalQueued {
+    pub pool: Pubkey,
+    pub id: u64,
+    pub proposer: Pubkey,
+    pub destination: Pubkey,
+    pub amount: u64,
+    pub execute_after: i64,
+}
+
+#[event]
+pub struct WithdrawalCancelled {
+    pub pool: Pubkey,
+    pub id: u64,
+    pub admin: Pubkey,
+}
+
+#[event]
+pub struct WithdrawalExecuted {
+    pub pool: Pubkey,
+    pub id: u64,
+    pub destination: Pubkey,
+    pub amount: u64,
+}
@@ -1,7 +1,11 @@
 use anchor_lang::{
     prelude::*,
+    AccountsClose,
+};
+use solana_program::{
+    program::{invoke_signed, invoke},
+    program_option::COption,
 };
-use solana_program::program::{invoke_signed, invoke};
 use anchor_spl::{
     token::{self, Token, TokenAccount, Transfer },
 };
@@ -13,10 +17,18 @@ use metaplex_token_metadata::{
 pub mod account;
 pub mod error;
 pub mod constants;
+pub mod event;
+pub mod migration;
+pub mod token_interface;
+pub mod utils;
 
 use account::*;
 use error::*;
 use constants::*;
+use event::*;
+use migration::*;
+use token_interface::*;
+use utils::*;
 
 declare_id!("2RbwYVj8gmYf8TRNukd34fGJgT7X4X4K3t6gLGwJkNQD");
 
@@ -24,139 +36,660 @@ declare_id!("2RbwYVj8gmYf8TRNukd34fGJgT7X4X4K3t6gLGwJkNQD");
 pub mod staking {
     use super::*;
 
-    pub fn initialize(ctx: Context<Initialize>, _global_bump: u8) -> Result<()> {
+    pub fn create_pool(
+        ctx: Context<CreatePool>,
+        _global_bump: u8,
+        pool_id: u64,
+        withdraw_delay: i64,
+        collections: Vec<AllowedCollection>,
+        tiers: Vec<RewardTier>,
+    ) -> Result<()> {
+        // Every withdrawal is queued until set_withdraw_timelock raises the threshold
+        require!(withdraw_delay >= MIN_WITHDRAW_DELAY, StakingError::InvalidWithdrawDelay);
         let global_authority = &mut ctx.accounts.global_authority;
+        global_authority.version = GLOBAL_POOL_VERSION;
+        global_authority.pool_id = pool_id;
+        global_authority.withdraw_delay = withdraw_delay;
         global_authority.super_admin = ctx.accounts.admin.key();
+        global_authority.reward_mint = ctx.accounts.reward_mint.key();
+        global_authority.rate_updated_time = Clock::get()?.unix_timestamp;
+
+        configure_pool(&mut ctx.accounts.reward_config, &mut ctx.accounts.collection_registry, collections, tiers)?;
+        emit!(PoolCreated {
+            pool: ctx.accounts.global_authority.key(),
+            pool_id,
+            admin: ctx.accounts.admin.key(),
+            reward_mint: ctx.accounts.reward_mint.key(),
+        });
+        Ok(())
+    }
+
+    // Convert the unversioned global state in place and create the accounts it now depends on
+    pub fn migrate_global_pool(
+        ctx: Context<MigrateGlobalPool>,
+        collections: Vec<AllowedCollection>,
+        tiers: Vec<RewardTier>,
+    ) -> Result<()> {
+        let global_info = ctx.accounts.global_authority.to_account_info();
+        let legacy = LegacyGlobalPool::try_from_bytes(&global_info.try_borrow_data()?)?;
+        require!(ctx.accounts.admin.key() == legacy.super_admin, StakingError::InvalidSuperOwner);
+
+        let global_pool = legacy.migrate(ctx.accounts.reward_mint.key(), Clock::get()?.unix_timestamp);
+        realloc_account(
+            &global_info,
+            &ctx.accounts.admin.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            GLOBAL_POOL_SIZE,
+        )?;
+        global_pool.try_serialize(&mut &mut global_info.try_borrow_mut_data()?[..])?;
+
+        configure_pool(&mut ctx.accounts.reward_config, &mut ctx.accounts.collection_registry, collections, tiers)?;
+        msg!("GlobalPool Migrated To Version {}", GLOBAL_POOL_VERSION);
+        Ok(())
+    }
+
+    // Convert an unversioned user pool in place, legacy stakes keep the reward the program paid for their duration
+    #[access_control(not_paused(&ctx.accounts.global_authority, PAUSE_STAKING))]
+    pub fn migrate_user_pool(ctx: Context<MigrateUserPool>) -> Result<()> {
+        let user_pool_info = ctx.accounts.user_pool.to_account_info();
+        // Legacy user pools all belong to the original pool
+        require!(ctx.accounts.global_authority.pool_id == 0, StakingError::InvalidGlobalPool);
+        let legacy = LegacyUserPool::try_from_bytes(&user_pool_info.try_borrow_data()?)?;
+        require!(ctx.accounts.owner.key() == legacy.owner, StakingError::InvalidUserPool);
+
+        realloc_account(
+            &user_pool_info,
+            &ctx.accounts.owner.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            MIGRATED_USER_POOL_SIZE,
+        )?;
+        // Entries move to a wider stride, clear the old layout before rewriting them
+        user_pool_info.try_borrow_mut_data()?[8..].fill(0);
+
+        let mut user_pool = UserPoolMut::load(&user_pool_info)?;
+        let pool = ctx.accounts.global_authority.key();
+        legacy.migrate(&mut user_pool, &mut ctx.accounts.global_authority, pool);
+        msg!("UserPool Migrated To Version {}", USER_POOL_VERSION);
+        Ok(())
+    }
+
+    // Move a keypair user pool to its PDA. Pools with more than STAKE_INITIAL_CAPACITY stakes
+    // take another call per STAKE_CAPACITY_STEP entries, until the PDA can hold all of them.
+    #[access_control(not_paused(&ctx.accounts.global_authority, PAUSE_STAKING))]
+    pub fn migrate_user_pool_to_pda(ctx: Context<MigrateUserPoolToPda>) -> Result<()> {
+        let legacy_info = ctx.accounts.legacy_user_pool.to_account_info();
+        let user_pool_info = ctx.accounts.user_pool.to_account_info();
+        let staked_count = {
+            let legacy = UserPoolMut::load(&legacy_info)?;
+            require!(legacy.owner == ctx.accounts.owner.key(), StakingError::InvalidUserPool);
+            require!(legacy.pool == ctx.accounts.global_authority.key(), StakingError::InvalidUserPool);
+            legacy.staked_count as usize
+        };
+        let target_len = USER_POOL_HEADER_SIZE + staked_count.max(STAKE_INITIAL_CAPACITY) * STAKED_DATA_SIZE;
+
+        let owner = ctx.accounts.owner.to_account_info();
+        let system_program = ctx.accounts.system_program.to_account_info();
+        if user_pool_info.data_is_empty() {
+            let global_key = ctx.accounts.global_authority.key();
+            let bump = *ctx.bumps.get("user_pool").unwrap();
+            let seeds = &[USER_POOL_SEED.as_bytes(), global_key.as_ref(), owner.key.as_ref(), &[bump]];
+            create_pda_account(&user_pool_info, &owner, &system_program, USER_POOL_SIZE, &[&seeds[..]])?;
+        } else {
+            // Only a PDA this migration created is still missing its discriminator
+            require!(user_pool_info.try_borrow_data()?[..8] == [0; 8], StakingError::UserPoolExists);
+            let new_len = (user_pool_info.data_len() + STAKE_CAPACITY_STEP * STAKED_DATA_SIZE).min(target_len);
+            realloc_account(&user_pool_info, &owner, &system_program, new_len)?;
+        }
+        if user_pool_info.data_len() < target_len {
+            msg!("UserPool Grown To {} Of {} Bytes", user_pool_info.data_len(), target_len);
+            return Ok(());
+        }
+
+        let len = USER_POOL_HEADER_SIZE + staked_count * STAKED_DATA_SIZE;
+        user_pool_info.try_borrow_mut_data()?[..len].copy_from_slice(&legacy_info.try_borrow_data()?[..len]);
+
+        // Stake receipts only hold the entry index, so they stay valid
+        let lamports = legacy_info.lamports();
+        **legacy_info.try_borrow_mut_lamports()? = 0;
+        **owner.try_borrow_mut_lamports()? += lamports;
+        legacy_info.try_borrow_mut_data()?.fill(0);
+        msg!("UserPool Migrated To {:?}", user_pool_info.key());
+        Ok(())
+    }
+
+    // Backfill the receipt of an NFT staked before receipts existed, unstaking needs it
+    #[access_control(
+        user(&ctx.accounts.user_pool, &ctx.accounts.owner, &ctx.accounts.global_authority)
+        not_paused(&ctx.accounts.global_authority, PAUSE_STAKING)
+    )]
+    pub fn issue_stake_receipt(ctx: Context<IssueStakeReceipt>) -> Result<()> {
+        let user_pool = UserPoolMut::load(ctx.accounts.user_pool.as_ref())?;
+        let index = user_pool.find_nft(ctx.accounts.nft_mint.key)?;
+        ctx.accounts.stake_receipt.issue(
+            ctx.accounts.owner.key(),
+            ctx.accounts.global_authority.key(),
+            index,
+            &user_pool.staking[index],
+            *ctx.bumps.get("stake_receipt").unwrap(),
+        );
+        msg!("Stake Receipt: {:?} Index: {}", ctx.accounts.stake_receipt.key(), index);
+        Ok(())
+    }
+
+    pub fn propose_admin(
+        ctx: Context<ManageGlobalPool>,
+        new_admin: Pubkey,
+    ) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+        require!(ctx.accounts.admin.key() == global_authority.super_admin, StakingError::InvalidSuperOwner);
+        require!(new_admin != Pubkey::default(), StakingError::InvalidPendingAdmin);
+
+        global_authority.pending_admin = new_admin;
+        emit!(AdminProposed {
+            pool: global_authority.key(),
+            admin: ctx.accounts.admin.key(),
+            pending_admin: new_admin,
+        });
+        Ok(())
+    }
+
+    pub fn cancel_admin_transfer(ctx: Context<ManageGlobalPool>) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+        require!(ctx.accounts.admin.key() == global_authority.super_admin, StakingError::InvalidSuperOwner);
+        require!(global_authority.pending_admin != Pubkey::default(), StakingError::InvalidPendingAdmin);
+
+        let pending_admin = global_authority.pending_admin;
+        global_authority.pending_admin = Pubkey::default();
+        emit!(AdminTransferCancelled {
+            pool: global_authority.key(),
+            admin: ctx.accounts.admin.key(),
+            pending_admin,
+        });
+        Ok(())
+    }
+
+    // Must be signed by the proposed admin, so a mistyped key can't take over
+    pub fn accept_admin(ctx: Context<ManageGlobalPool>) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+        require!(
+            global_authority.pending_admin != Pubkey::default() && ctx.accounts.admin.key() == global_authority.pending_admin,
+            StakingError::InvalidPendingAdmin
+        );
+
+        let previous_admin = global_authority.super_admin;
+        global_authority.super_admin = global_authority.pending_admin;
+        global_authority.pending_admin = Pubkey::default();
+        emit!(AdminTransferred {
+            pool: global_authority.key(),
+            previous_admin,
+            admin: global_authority.super_admin,
+        });
+        Ok(())
+    }
+
+    pub fn grant_role(
+        ctx: Context<ManageGlobalPool>,
+        role: u8,
+        account: Pubkey,
+    ) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+        require!(ctx.accounts.admin.key() == global_authority.super_admin, StakingError::InvalidSuperOwner);
+        require!((role as usize) < ROLE_COUNT, StakingError::InvalidRole);
+
+        global_authority.roles[role as usize] = account;
+        emit!(RoleUpdated {
+            pool: global_authority.key(),
+            admin: ctx.accounts.admin.key(),
+            role,
+            account,
+        });
+        Ok(())
+    }
+
+    pub fn revoke_role(
+        ctx: Context<ManageGlobalPool>,
+        role: u8,
+    ) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+        require!(ctx.accounts.admin.key() == global_authority.super_admin, StakingError::InvalidSuperOwner);
+        require!((role as usize) < ROLE_COUNT, StakingError::InvalidRole);
+
+        global_authority.roles[role as usize] = Pubkey::default();
+        emit!(RoleUpdated {
+            pool: global_authority.key(),
+            admin: ctx.accounts.admin.key(),
+            role,
+            account: Pubkey::default(),
+        });
+        Ok(())
+    }
+
+    #[access_control(has_role(&ctx.accounts.global_authority, &ctx.accounts.admin, ROLE_OPERATOR))]
+    pub fn add_reward_tier(
+        ctx: Context<ManageRewardConfig>,
+        duration: i64,
+        reward: u64,
+    ) -> Result<()> {
+        ctx.accounts.reward_config.add_tier(duration, reward)?;
+        msg!("Added Tier: {} days => {}", duration, reward);
+        Ok(())
+    }
+
+    #[access_control(has_role(&ctx.accounts.global_authority, &ctx.accounts.admin, ROLE_OPERATOR))]
+    pub fn update_reward_tier(
+        ctx: Context<ManageRewardConfig>,
+        duration: i64,
+        reward: u64,
+    ) -> Result<()> {
+        ctx.accounts.reward_config.update_tier(duration, reward)?;
+        msg!("Updated Tier: {} days => {}", duration, reward);
+        Ok(())
+    }
+
+    #[access_control(has_role(&ctx.accounts.global_authority, &ctx.accounts.admin, ROLE_OPERATOR))]
+    pub fn retire_reward_tier(
+        ctx: Context<ManageRewardConfig>,
+        duration: i64,
+    ) -> Result<()> {
+        ctx.accounts.reward_config.retire_tier(duration)?;
+        msg!("Retired Tier: {} days", duration);
+        Ok(())
+    }
+
+    #[access_control(has_role(&ctx.accounts.global_authority, &ctx.accounts.admin, ROLE_OPERATOR))]
+    pub fn set_reward_mode(
+        ctx: Context<ManageGlobalPool>,
+        reward_mode: u8,
+    ) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+        require!(
+            reward_mode == REWARD_MODE_TIER || reward_mode == REWARD_MODE_CONTINUOUS,
+            StakingError::InvalidRewardMode
+        );
+
+        global_authority.reward_mode = reward_mode;
+        msg!("Reward Mode: {}", reward_mode);
+        Ok(())
+    }
+
+    #[access_control(has_role(&ctx.accounts.global_authority, &ctx.accounts.admin, ROLE_OPERATOR))]
+    pub fn set_reward_rate(
+        ctx: Context<ManageGlobalPool>,
+        reward_rate: u64,
+    ) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+
+        let timestamp = Clock::get()?.unix_timestamp;
+        global_authority.set_reward_rate(reward_rate, timestamp);
+        msg!("Reward Rate: {} Updated Time: {}", reward_rate, timestamp);
+        Ok(())
+    }
+
+    pub fn set_reward_funding(
+        ctx: Context<SetRewardFunding>,
+        reward_funding: u8,
+        emission_cap: u64,
+    ) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+        require!(ctx.accounts.admin.key() == global_authority.super_admin, StakingError::InvalidSuperOwner);
+        require!(
+            reward_funding == REWARD_FUNDING_VAULT || reward_funding == REWARD_FUNDING_MINT,
+            StakingError::InvalidRewardFunding
+        );
+        require!(emission_cap >= global_authority.emitted, StakingError::InvalidRewardFunding);
+        if reward_funding == REWARD_FUNDING_MINT {
+            let mint = token_mint(&ctx.accounts.reward_mint)?;
+            require!(
+                mint.mint_authority == COption::Some(global_authority.key()),
+                StakingError::InvalidMintAuthority
+            );
+        }
+
+        global_authority.reward_funding = reward_funding;
+        global_authority.emission_cap = emission_cap;
+        emit!(RewardFundingUpdated {
+            pool: global_authority.key(),
+            admin: ctx.accounts.admin.key(),
+            reward_funding,
+            emission_cap,
+        });
+        Ok(())
+    }
+
+    #[access_control(has_role(&ctx.accounts.global_authority, &ctx.accounts.admin, ROLE_OPERATOR))]
+    pub fn add_reward_stream(
+        ctx: Context<AddRewardStream>,
+        reward_mode: u8,
+        reward_rate: u64,
+        tiers: Vec<RewardTier>,
+    ) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+        let reward_stream = &mut ctx.accounts.reward_stream;
+        require!(
+            (global_authority.stream_count as usize) < MAX_REWARD_STREAMS,
+            StakingError::RewardStreamLimitReached
+        );
+        require!(
+            reward_mode == REWARD_MODE_TIER || reward_mode == REWARD_MODE_CONTINUOUS,
+            StakingError::InvalidRewardMode
+        );
+
+        reward_stream.pool = global_authority.key();
+        reward_stream.index = global_authority.stream_count as u8;
+        reward_stream.mint = ctx.accounts.stream_mint.key();
+        reward_stream.reward_mode = reward_mode;
+        reward_stream.set_reward_rate(reward_rate, Clock::get()?.unix_timestamp)?;
+        reward_stream.set_tiers(tiers)?;
+        global_authority.stream_count += 1;
+        emit!(RewardStreamAdded {
+            pool: global_authority.key(),
+            admin: ctx.accounts.admin.key(),
+            stream: reward_stream.key(),
+            index: reward_stream.index,
+            mint: reward_stream.mint,
+        });
+        Ok(())
+    }
+
+    #[access_control(has_role(&ctx.accounts.global_authority, &ctx.accounts.admin, ROLE_OPERATOR))]
+    pub fn set_stream_reward_rate(
+        ctx: Context<ManageRewardStream>,
+        reward_rate: u64,
+    ) -> Result<()> {
+        let timestamp = Clock::get()?.unix_timestamp;
+        ctx.accounts.reward_stream.set_reward_rate(reward_rate, timestamp)?;
+        msg!("Stream: {} Reward Rate: {} Updated Time: {}", ctx.accounts.reward_stream.index, reward_rate, timestamp);
+        Ok(())
+    }
+
+    #[access_control(has_role(&ctx.accounts.global_authority, &ctx.accounts.admin, ROLE_OPERATOR))]
+    pub fn set_stream_tiers(
+        ctx: Context<ManageRewardStream>,
+        tiers: Vec<RewardTier>,
+    ) -> Result<()> {
+        ctx.accounts.reward_stream.set_tiers(tiers)?;
+        msg!("Stream: {} Tiers: {}", ctx.accounts.reward_stream.index, ctx.accounts.reward_stream.tier_count);
+        Ok(())
+    }
+
+    #[access_control(has_role(&ctx.accounts.global_authority, &ctx.accounts.admin, ROLE_OPERATOR))]
+    pub fn set_early_unstake_policy(
+        ctx: Context<ManageGlobalPool>,
+        policy: u8,
+        penalty: u8,
+        burn_penalty: bool,
+    ) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+        require!(
+            (policy == EARLY_UNSTAKE_REJECT || policy == EARLY_UNSTAKE_PENALTY) && penalty <= 100,
+            StakingError::InvalidEarlyUnstakePolicy
+        );
+
+        global_authority.early_unstake_policy = policy;
+        global_authority.early_unstake_penalty = penalty;
+        global_authority.burn_penalty = burn_penalty;
+        msg!("Early Unstake Policy: {} Penalty: {}% Burn: {}", policy, penalty, burn_penalty);
+        Ok(())
+    }
+
+    #[access_control(has_role(&ctx.accounts.global_authority, &ctx.accounts.admin, ROLE_PAUSER))]
+    pub fn set_paused(
+        ctx: Context<ManageGlobalPool>,
+        paused: u8,
+    ) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+        require!(paused & !PAUSE_ALL == 0, StakingError::InvalidPauseFlags);
+
+        let previous = global_authority.paused;
+        global_authority.paused = paused;
+        emit!(PauseUpdated {
+            pool: global_authority.key(),
+            admin: ctx.accounts.admin.key(),
+            previous,
+            paused,
+        });
+        Ok(())
+    }
+
+    #[access_control(has_role(&ctx.accounts.global_authority, &ctx.accounts.admin, ROLE_PAUSER))]
+    pub fn set_emergency(
+        ctx: Context<ManageGlobalPool>,
+        emergency: bool,
+    ) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+
+        global_authority.emergency = emergency;
+        emit!(EmergencyUpdated {
+            pool: global_authority.key(),
+            admin: ctx.accounts.admin.key(),
+            emergency,
+        });
+        Ok(())
+    }
+
+    #[access_control(has_role(&ctx.accounts.global_authority, &ctx.accounts.admin, ROLE_OPERATOR))]
+    pub fn add_collection(
+        ctx: Context<ManageCollectionRegistry>,
+        collection: AllowedCollection,
+    ) -> Result<()> {
+        require!(collection.verify_creator || collection.verify_collection, StakingError::InvalidCollectionGate);
+
+        ctx.accounts.collection_registry.add_collection(collection)?;
+        msg!(
+            "Collection: {:?} Stakeable: {} Fusable: {} Creator: {} Collection: {}",
+            collection.address,
+            collection.stakeable,
+            collection.fusable,
+            collection.verify_creator,
+            collection.verify_collection
+        );
+        Ok(())
+    }
+
+    #[access_control(has_role(&ctx.accounts.global_authority, &ctx.accounts.admin, ROLE_OPERATOR))]
+    pub fn remove_collection(
+        ctx: Context<ManageCollectionRegistry>,
+        address: Pubkey,
+    ) -> Result<()> {
+        ctx.accounts.collection_registry.remove_collection(address)?;
+        msg!("Removed Collection: {:?}", address);
         Ok(())
     }
 
+    pub fn quote_reward(
+        ctx: Context<QuoteReward>,
+        duration: i64,
+    ) -> Result<u64> {
+        let tier = ctx.accounts.reward_config.find_tier(duration).ok_or(StakingError::InvalidStakeDuration)?;
+        msg!("Quote: {} days => {}", duration, tier.reward);
+        Ok(tier.reward)
+    }
+
+    #[access_control(not_paused(&ctx.accounts.global_authority, PAUSE_STAKING))]
     pub fn initialize_user_pool(
         ctx: Context<InitializeUserPool>
     ) -> Result<()> {
         let mut user_pool = ctx.accounts.user_pool.load_init()?;
-        let user_vault = &mut ctx.accounts.user_vault;
+        user_pool.version = USER_POOL_VERSION;
         user_pool.owner = ctx.accounts.owner.key();
+        user_pool.pool = ctx.accounts.global_authority.key();
+        Ok(())
+    }
+
+    // Every pool has its own vault per wallet holding that pool's reward mint.
+    // The original pool's vault keeps its address, its id seed is empty.
+    #[access_control(not_paused(&ctx.accounts.global_authority, PAUSE_VAULT))]
+    pub fn initialize_user_vault(
+        ctx: Context<InitializeUserVault>
+    ) -> Result<()> {
+        let user_vault = &mut ctx.accounts.user_vault;
         user_vault.owner = ctx.accounts.owner.key();
         user_vault.amount = 0;
         Ok(())
     }
 
+    // Return the rent of an empty user pool to its owner
+    #[access_control(
+        user(&ctx.accounts.user_pool, &ctx.accounts.owner, &ctx.accounts.global_authority)
+        not_paused(&ctx.accounts.global_authority, PAUSE_STAKING)
+    )]
+    pub fn close_user_pool(ctx: Context<CloseUserPool>) -> Result<()> {
+        let user_pool = ctx.accounts.user_pool.load()?;
+        require!(
+            user_pool.staked_count == 0
+                && user_pool.reward_owed == 0
+                && user_pool.stream_owed.iter().all(|owed| *owed == 0),
+            StakingError::UserPoolNotEmpty
+        );
+        msg!("UserPool Closed: {:?}", ctx.accounts.user_pool.key());
+        Ok(())
+    }
+
+    // Close an empty user vault along with its reward token account, returning all of their rent to the owner
+    #[access_control(not_paused(&ctx.accounts.global_authority, PAUSE_VAULT))]
+    pub fn close_user_vault(ctx: Context<CloseUserVault>) -> Result<()> {
+        require!(
+            ctx.accounts.user_vault.amount == 0 && token_account(&ctx.accounts.user_token_account)?.amount == 0,
+            StakingError::UserVaultNotEmpty
+        );
+
+        let owner_key = ctx.accounts.owner.key();
+        let pool_seed = pool_id_seed(ctx.accounts.global_authority.pool_id);
+        let seeds = &[VAULT_SEED.as_bytes(), pool_seed.as_slice(), owner_key.as_ref(), &[*ctx.bumps.get("user_vault").unwrap()]];
+        let signer = &[&seeds[..]];
+        close_account(
+            &ctx.accounts.token_program,
+            &ctx.accounts.user_token_account,
+            &ctx.accounts.owner.to_account_info(),
+            &ctx.accounts.user_vault.to_account_info(),
+            signer,
+        )?;
+        msg!("UserVault Closed: {:?}", ctx.accounts.user_vault.key());
+        Ok(())
+    }
+
+    #[access_control(not_paused(&ctx.accounts.global_authority, PAUSE_VAULT))]
     pub fn deposit_to_account(
         ctx: Context<DepositToAccount>,
         amount: u64
     ) -> Result<()> {
-        let user_vault = &mut ctx.accounts.user_vault;
-
-        let token_account_info = &mut &ctx.accounts.user_token_account;
-        let dest_token_account_info = &mut &ctx.accounts.dest_token_account;
-        let token_program = &mut &ctx.accounts.token_program;
-
-        let cpi_accounts = Transfer {
-            from: token_account_info.to_account_info().clone(),
-            to: dest_token_account_info.to_account_info().clone(),
-            authority: ctx.accounts.owner.to_account_info().clone()
-        };
-        token::transfer(
-            CpiContext::new(token_program.clone().to_account_info(), cpi_accounts),
-            amount
+        // Only what arrives after transfer fees is credited
+        let credited = transfer_checked(
+            &ctx.accounts.token_program,
+            &ctx.accounts.user_token_account,
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.dest_token_account,
+            &ctx.accounts.owner.to_account_info(),
+            &[],
+            amount,
         )?;
 
-        user_vault.amount += amount;
+        ctx.accounts.user_vault.credit(credited)?;
         
         Ok(())
     }
 
+    #[access_control(not_paused(&ctx.accounts.global_authority, PAUSE_VAULT))]
     pub fn withdraw_from_account(
         ctx: Context<WithdrawFromAccount>,
         bump: u8,
         amount: u64,
     ) -> Result<()> {
-        require!(ctx.accounts.user_vault.amount > amount, StakingError::InsufficientAccountVault);
-        let token_account_info = &mut &ctx.accounts.user_token_account;
-        let dest_token_account_info = &mut &ctx.accounts.dest_token_account;
-        let token_program = &mut &ctx.accounts.token_program;
-        let seeds = &[VAULT_SEED.as_bytes(), &ctx.accounts.owner.key().to_bytes(), &[bump]];
+        ctx.accounts.user_vault.debit(amount)?;
+        let pool_seed = pool_id_seed(ctx.accounts.global_authority.pool_id);
+        let seeds = &[VAULT_SEED.as_bytes(), pool_seed.as_slice(), &ctx.accounts.owner.key().to_bytes(), &[bump]];
         let signer = &[&seeds[..]];
 
-        let cpi_accounts = Transfer {
-            from: token_account_info.to_account_info().clone(),
-            to: dest_token_account_info.to_account_info().clone(),
-            authority: ctx.accounts.user_vault.to_account_info().clone()
-        };
-        token::transfer(
-            CpiContext::new_with_signer(token_program.to_account_info().clone(), cpi_accounts, signer),
-            amount
+        transfer_checked(
+            &ctx.accounts.token_program,
+            &ctx.accounts.user_token_account,
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.dest_token_account,
+            &ctx.accounts.user_vault.to_account_info(),
+            signer,
+            amount,
         )?;
-
-        ctx.accounts.user_vault.amount -= amount;
         
         Ok(())
     }
 
 
+    #[access_control(not_paused(&ctx.accounts.global_authority, PAUSE_VAULT))]
     pub fn deposit_to_vault(
         ctx: Context<DepositToVault>,
         bump: u8,
         amount: u64,
     ) -> Result<()> {
-        require!(ctx.accounts.user_token_account.amount >= amount, StakingError::InsufficientAccountVault);
+        require!(token_account(&ctx.accounts.user_token_account)?.amount >= amount, StakingError::InsufficientAccountVault);
 
-        let token_account_info = &mut &ctx.accounts.user_token_account;
-        let dest_token_account_info = &mut &ctx.accounts.reward_vault;
-        let token_program = &mut &ctx.accounts.token_program;
-        let seeds = &[VAULT_SEED.as_bytes(), &ctx.accounts.owner.key().to_bytes(), &[bump]];
+        let pool_seed = pool_id_seed(ctx.accounts.global_authority.pool_id);
+        let seeds = &[VAULT_SEED.as_bytes(), pool_seed.as_slice(), &ctx.accounts.owner.key().to_bytes(), &[bump]];
         let signer = &[&seeds[..]];
 
-        let cpi_accounts = Transfer {
-            from: token_account_info.to_account_info().clone(),
-            to: dest_token_account_info.to_account_info().clone(),
-            authority: ctx.accounts.user_vault.to_account_info().clone()
-        };
-        token::transfer(
-            CpiContext::new_with_signer(token_program.to_account_info().clone(), cpi_accounts, signer),
-            amount
+        transfer_checked(
+            &ctx.accounts.token_program,
+            &ctx.accounts.user_token_account,
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.user_vault.to_account_info(),
+            signer,
+            amount,
         )?;
 
-        ctx.accounts.user_vault.amount -= amount;
+        ctx.accounts.user_vault.debit(amount)?;
         
         Ok(())
     }
 
+    // Treasury funds moved into the treasurer's own user vault, bound by the same timelock as withdraw_token
+    #[access_control(
+        has_role(&ctx.accounts.global_authority, &ctx.accounts.owner, ROLE_TREASURER)
+        not_paused(&ctx.accounts.global_authority, PAUSE_VAULT)
+    )]
     pub fn withdraw_from_vault(
         ctx: Context<WithdrawFromVault>,
         global_bump: u8,
         amount: u64,
     ) -> Result<()> {
-        require!(ctx.accounts.reward_vault.amount > amount, StakingError::InsufficientRewardVault);
+        require!(amount <= ctx.accounts.global_authority.withdraw_threshold, StakingError::WithdrawalRequiresTimelock);
+        let vault_balance = token_account(&ctx.accounts.reward_vault)?.amount;
+        require!(vault_balance > amount, StakingError::InsufficientRewardVault);
+        let timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            ctx.accounts.global_authority.free_reward_balance(vault_balance, timestamp) >= amount,
+            StakingError::RewardVaultInsolvent
+        );
 
-        let token_account_info = &mut &ctx.accounts.reward_vault;
-        let dest_token_account_info = &mut &ctx.accounts.dest_token_account;
-        let token_program = &mut &ctx.accounts.token_program;
-        let seeds = &[GLOBAL_AUTHORITY_SEED.as_bytes(), &[global_bump]];
+        let pool_seed = pool_id_seed(ctx.accounts.global_authority.pool_id);
+        let seeds = &[GLOBAL_AUTHORITY_SEED.as_bytes(), pool_seed.as_slice(), &[global_bump]];
         let signer = &[&seeds[..]];
 
-        let cpi_accounts = Transfer {
-            from: token_account_info.to_account_info().clone(),
-            to: dest_token_account_info.to_account_info().clone(),
-            authority: ctx.accounts.global_authority.to_account_info().clone()
-        };
-        token::transfer(
-            CpiContext::new_with_signer(token_program.to_account_info().clone(), cpi_accounts, signer),
-            amount
+        let credited = transfer_checked(
+            &ctx.accounts.token_program,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.dest_token_account,
+            &ctx.accounts.global_authority.to_account_info(),
+            signer,
+            amount,
         )?;
 
-        ctx.accounts.user_vault.amount += amount;
+        ctx.accounts.user_vault.credit(credited)?;
         
         Ok(())
     }
 
+    #[access_control(
+        has_role(&ctx.accounts.global_authority, &ctx.accounts.update_authority, ROLE_FUSION_SIGNER)
+        not_paused(&ctx.accounts.global_authority, PAUSE_FUSION)
+    )]
     pub fn fusion(
         ctx: Context<Fusion>,
         bump: u8,
         amount: u64,
         new_uri: String
     ) -> Result<()> {
-        require!(ctx.accounts.user_token_account.amount >= amount, StakingError::InsufficientAccountVault);
+        require!(token_account(&ctx.accounts.user_token_account)?.amount >= amount, StakingError::InsufficientAccountVault);
 
         let mint_metadata = &mut &ctx.accounts.mint_metadata;
 
@@ -179,30 +712,21 @@ pub mod staking {
         );
 
         // Verify metadata is legit
-        let mut nft_metadata = Metadata::from_account_info(mint_metadata)?;
+        let (nft_metadata, nft_collection) = parse_metadata(&mint_metadata.data.borrow())?;
     
         // Check if this NFT is the wanted collection and verified
-        // Check if its CandyMachinID is true
-        if let Some(creators) = nft_metadata.data.creators {
-            let mut valid: u8 = 0;
-            let mut collection: Pubkey = Pubkey::default();
-            for creator in creators {
-                if creator.address.to_string() == COLLECTION_ADDRESS && creator.verified == true {
-                    valid = 1;
-                    collection = creator.address;
-                    break;
-                }
-            }
-            require!(valid == 1, StakingError::UnkownOrNotAllowedNFTCollection);
-            msg!("Collection= {:?}", collection);
-        } else {
-            return Err(error!(StakingError::MetadataCreatorParseError));
-        };
+        let collection = find_allowed_collection(
+            &ctx.accounts.collection_registry,
+            nft_metadata.data.creators.as_ref(),
+            nft_collection,
+            |entry| entry.fusable,
+        )?;
+        msg!("Collection= {:?}", collection);
 
         let update_authority = &mut ctx.accounts.update_authority;
         let token_metadata_program = &mut ctx.accounts.token_metadata_program;
 
-        nft_metadata = Metadata::from_account_info(mint_metadata)?.clone();
+        let mut nft_metadata = Metadata::from_account_info(mint_metadata)?;
         // Update metadata
         nft_metadata.data.uri = new_uri;
 
@@ -222,72 +746,79 @@ pub mod staking {
             ],
         )?;
 
-        let token_account_info = &mut &ctx.accounts.user_token_account;
-        let dest_token_account_info = &mut &ctx.accounts.reward_vault;
-        let token_program = &mut &ctx.accounts.token_program;
-        let seeds = &[VAULT_SEED.as_bytes(), &ctx.accounts.owner.key().to_bytes(), &[bump]];
+        let pool_seed = pool_id_seed(ctx.accounts.global_authority.pool_id);
+        let seeds = &[VAULT_SEED.as_bytes(), pool_seed.as_slice(), &ctx.accounts.owner.key().to_bytes(), &[bump]];
         let signer = &[&seeds[..]];
 
-        let cpi_accounts = Transfer {
-            from: token_account_info.to_account_info().clone(),
-            to: dest_token_account_info.to_account_info().clone(),
-            authority: ctx.accounts.user_vault.to_account_info().clone()
-        };
-        token::transfer(
-            CpiContext::new_with_signer(token_program.to_account_info().clone(), cpi_accounts, signer),
-            amount
+        transfer_checked(
+            &ctx.accounts.token_program,
+            &ctx.accounts.user_token_account,
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.user_vault.to_account_info(),
+            signer,
+            amount,
         )?;
 
-        ctx.accounts.user_vault.amount -= amount;
+        ctx.accounts.user_vault.debit(amount)?;
         
         Ok(())
     }
 
-    #[access_control(user(&ctx.accounts.user_pool, &ctx.accounts.owner))]
-    pub fn stake_nft_to_pool(
-        ctx: Context<StakeNftToPool>,
+    #[access_control(
+        user(&ctx.accounts.user_pool, &ctx.accounts.owner, &ctx.accounts.global_authority)
+        not_paused(&ctx.accounts.global_authority, PAUSE_STAKING)
+    )]
+    pub fn stake_nft_to_pool<'info>(
+        ctx: Context<'_, '_, '_, 'info, StakeNftToPool<'info>>,
         _global_bump: u8,
         duration: i64,
     ) -> Result<()> {
-        let mint_metadata = &mut &ctx.accounts.mint_metadata;
-
         msg!("Metadata Account: {:?}", ctx.accounts.mint_metadata.key());
-        let (metadata, _) = Pubkey::find_program_address(
-            &[
-                metaplex_token_metadata::state::PREFIX.as_bytes(),
-                metaplex_token_metadata::id().as_ref(),
-                ctx.accounts.nft_mint.key().as_ref(),
-            ],
-            &metaplex_token_metadata::id(),
-        );
-        require!(metadata == mint_metadata.key(), StakingError::InvalidMetadata);
-
-        // verify metadata is legit
-        let nft_metadata = Metadata::from_account_info(mint_metadata)?;
-
-        if let Some(creators) = nft_metadata.data.creators {
-            let mut valid: u8 = 0;
-            let mut collection: Pubkey = Pubkey::default();
-            for creator in creators {       
-                if creator.address.to_string() == COLLECTION_ADDRESS_2D && creator.verified == true {
-                    valid = 1;
-                    collection = creator.address;
-                    break;
-                }
-            }
-            require!(valid == 1, StakingError::UnkownOrNotAllowedNFTCollection);
-            msg!("Collection= {:?}", collection);
-        } else {
-            return Err(error!(StakingError::MetadataCreatorParseError));
-        };
-
-        let mut user_pool = ctx.accounts.user_pool.load_mut()?;
+        let collection = stakeable_collection(
+            ctx.accounts.nft_mint.key,
+            &ctx.accounts.mint_metadata,
+            &ctx.accounts.collection_registry,
+        )?;
+        msg!("Collection= {:?}", collection);
+
+        // Make room for one more entry once the user pool is full, the owner pays the extra rent
+        let user_pool_info = ctx.accounts.user_pool.as_ref();
+        let grown_len = UserPoolMut::load(user_pool_info)?.grown_len(1)?;
+        if let Some(new_len) = grown_len {
+            realloc_account(
+                user_pool_info,
+                &ctx.accounts.owner.to_account_info(),
+                &ctx.accounts.system_program.to_account_info(),
+                new_len,
+            )?;
+            msg!("UserPool Grown To {} Bytes", new_len);
+        }
+
+        let mut user_pool = UserPoolMut::load(user_pool_info)?;
         msg!("Stake Mint: {:?}", ctx.accounts.nft_mint.key());
         msg!("Duration: {}", duration);
+        let tier = ctx.accounts.reward_config.find_tier(duration).ok_or(StakingError::InvalidStakeDuration)?;
+        let stream_accounts = load_reward_streams(ctx.remaining_accounts, &ctx.accounts.global_authority)?;
+        let streams: Vec<&RewardStream> = stream_accounts.iter().map(|stream| &**stream).collect();
         let timestamp = Clock::get()?.unix_timestamp;
-        user_pool.add_nft(ctx.accounts.nft_mint.key(), duration, timestamp);
+        let global_authority = &mut ctx.accounts.global_authority;
+        let index = user_pool.add_nft(ctx.accounts.nft_mint.key(), tier, timestamp, global_authority, &streams)?;
+        ctx.accounts.stake_receipt.issue(
+            ctx.accounts.owner.key(),
+            global_authority.key(),
+            index,
+            &user_pool.staking[index],
+            *ctx.bumps.get("stake_receipt").unwrap(),
+        );
         msg!("Staked Time: {}", timestamp);
-        ctx.accounts.global_authority.total_staked_count += 1;
+        global_authority.total_staked_count += 1;
+
+        // The vault, or what is left under the emission cap, must still cover every promised reward including this one
+        require!(
+            global_authority.is_solvent(token_account(&ctx.accounts.reward_vault)?.amount, timestamp),
+            StakingError::RewardVaultInsolvent
+        );
 
         let token_account_info = &mut &ctx.accounts.user_nft_token_account;
         let dest_token_account_info = &mut &ctx.accounts.dest_nft_token_account;
@@ -306,23 +837,36 @@ pub mod staking {
         Ok(())
     }
     
-    #[access_control(user(&ctx.accounts.user_pool, &ctx.accounts.owner))]
-    pub fn withdraw_nft_from_pool(
-        ctx: Context<WithdrawNftFromPool>,
+    #[access_control(
+        user(&ctx.accounts.user_pool, &ctx.accounts.owner, &ctx.accounts.global_authority)
+        not_paused(&ctx.accounts.global_authority, PAUSE_UNSTAKING)
+    )]
+    pub fn withdraw_nft_from_pool<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawNftFromPool<'info>>,
         global_bump: u8,
     ) -> Result<()> {
-        let mut user_pool = ctx.accounts.user_pool.load_mut()?;
+        let mut user_pool = UserPoolMut::load(ctx.accounts.user_pool.as_ref())?;
         msg!("Staked Mint: {:?}", ctx.accounts.nft_mint.key());
 
+        let payouts = load_stream_payouts(ctx.remaining_accounts, &ctx.accounts.global_authority, ctx.accounts.owner.key)?;
+        let streams: Vec<&RewardStream> = payouts.iter().map(|payout| &*payout.stream).collect();
         let timestamp = Clock::get()?.unix_timestamp;
-        let reward: u64 = user_pool.remove_nft(ctx.accounts.nft_mint.key(), timestamp)?;
-        msg!("Reward: {:?} Unstaked Time: {}", reward, timestamp);
+        let (reward, penalty, stream_rewards) =
+            user_pool.remove_nft(
+                &ctx.accounts.stake_receipt,
+                &mut ctx.accounts.last_receipt,
+                timestamp,
+                &mut ctx.accounts.global_authority,
+                &streams,
+            )?;
+        msg!("Reward: {:?} Penalty: {:?} Unstaked Time: {}", reward, penalty, timestamp);
         ctx.accounts.global_authority.total_staked_count -= 1;
 
         let token_account_info = &mut &ctx.accounts.user_nft_token_account;
         let dest_token_account_info = &mut &ctx.accounts.dest_nft_token_account;
         let token_program = &mut &ctx.accounts.token_program;
-        let seeds = &[GLOBAL_AUTHORITY_SEED.as_bytes(), &[global_bump]];
+        let pool_seed = pool_id_seed(ctx.accounts.global_authority.pool_id);
+        let seeds = &[GLOBAL_AUTHORITY_SEED.as_bytes(), pool_seed.as_slice(), &[global_bump]];
         let signer = &[&seeds[..]];
 
         let cpi_accounts = Transfer {
@@ -334,15 +878,40 @@ pub mod staking {
             CpiContext::new_with_signer(token_program.clone().to_account_info(), cpi_accounts, signer),
             1
         )?;
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.reward_vault.to_account_info(),
-            to: ctx.accounts.user_reward_account.to_account_info(),
-            authority: ctx.accounts.global_authority.to_account_info()
-        };
-        token::transfer(
-            CpiContext::new_with_signer(token_program.to_account_info().clone(), cpi_accounts, signer),
-            reward
+        let (credited, unpaid) = pay_reward(
+            &mut ctx.accounts.global_authority,
+            &ctx.accounts.reward_token_program,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.user_reward_account,
+            signer,
+            reward,
         )?;
+        ctx.accounts.user_vault.credit(credited)?;
+        // Past the emission cap the reward waits for the cap to be raised, see claim_rewards
+        user_pool.owe_reward(unpaid, &mut ctx.accounts.global_authority);
+
+        // Retained penalties simply stay in the reward vault, minted pools never mint them
+        if penalty > 0
+            && ctx.accounts.global_authority.burn_penalty
+            && ctx.accounts.global_authority.reward_funding == REWARD_FUNDING_VAULT
+        {
+            burn_checked(
+                &ctx.accounts.reward_token_program,
+                &ctx.accounts.reward_vault,
+                &ctx.accounts.reward_mint,
+                &ctx.accounts.global_authority.to_account_info(),
+                signer,
+                penalty,
+            )?;
+        }
+
+        for (index, payout) in payouts.iter().enumerate() {
+            let authority = ctx.accounts.global_authority.to_account_info();
+            if !pay_stream(payout, &authority, signer, stream_rewards[index])? {
+                user_pool.stream_owed[index] += stream_rewards[index];
+            }
+        }
 
         invoke_signed(
             &spl_token::instruction::close_account(
@@ -364,31 +933,427 @@ pub mod staking {
         Ok(())
     }
 
-    pub fn withdraw_token(
-        ctx: Context<WithdrawToken>,
-        bump: u8,
-        amount: u64,
+    #[access_control(
+        user(&ctx.accounts.user_pool, &ctx.accounts.owner, &ctx.accounts.global_authority)
+        not_paused(&ctx.accounts.global_authority, PAUSE_STAKING)
+    )]
+    pub fn stake_nfts_batch<'info>(
+        ctx: Context<'_, '_, '_, 'info, StakeNftsBatch<'info>>,
+        duration: i64,
     ) -> Result<()> {
+        // The pool's reward streams come first, followed by the NFTs
+        let stream_count = ctx.accounts.global_authority.stream_count as usize;
+        require!(ctx.remaining_accounts.len() >= stream_count, StakingError::InvalidRewardStreams);
+        let (stream_infos, nft_infos) = ctx.remaining_accounts.split_at(stream_count);
+        let stream_accounts = load_reward_streams(stream_infos, &ctx.accounts.global_authority)?;
+        let streams: Vec<&RewardStream> = stream_accounts.iter().map(|stream| &**stream).collect();
+        let owner = ctx.accounts.owner.key();
+        let pool = ctx.accounts.global_authority.key();
+        let entries = load_stake_batch(nft_infos, &owner, &pool, &ctx.accounts.collection_registry)?;
+
+        // Make room for the whole batch at once, the owner pays the extra rent
+        let user_pool_info = ctx.accounts.user_pool.as_ref();
+        let grown_len = UserPoolMut::load(user_pool_info)?.grown_len(entries.len())?;
+        if let Some(new_len) = grown_len {
+            realloc_account(
+                user_pool_info,
+                &ctx.accounts.owner.to_account_info(),
+                &ctx.accounts.system_program.to_account_info(),
+                new_len,
+            )?;
+            msg!("UserPool Grown To {} Bytes", new_len);
+        }
+
+        let mut user_pool = UserPoolMut::load(user_pool_info)?;
+        msg!("Duration: {}", duration);
+        let tier = ctx.accounts.reward_config.find_tier(duration).ok_or(StakingError::InvalidStakeDuration)?;
+        let timestamp = Clock::get()?.unix_timestamp;
+        let owner_info = ctx.accounts.owner.to_account_info();
+        let system_program = ctx.accounts.system_program.to_account_info();
+        let token_program = ctx.accounts.token_program.to_account_info();
+        for entry in entries.iter() {
+            let index = user_pool.add_nft(entry.nft_mint, tier, timestamp, &mut ctx.accounts.global_authority, &streams)?;
+            create_pda_account(
+                &entry.stake_receipt,
+                &owner_info,
+                &system_program,
+                STAKE_RECEIPT_SIZE,
+                &[&[STAKE_RECEIPT_SEED.as_bytes(), entry.nft_mint.as_ref(), &[entry.receipt_bump]]],
+            )?;
+            let mut stake_receipt = Account::<StakeReceipt>::try_from_unchecked(&entry.stake_receipt)?;
+            stake_receipt.issue(owner, pool, index, &user_pool.staking[index], entry.receipt_bump);
+            stake_receipt.exit(&crate::ID)?;
+
+            let cpi_accounts = Transfer {
+                from: entry.user_nft_token_account.clone(),
+                to: entry.dest_nft_token_account.clone(),
+                authority: owner_info.clone(),
+            };
+            token::transfer(CpiContext::new(token_program.clone(), cpi_accounts), 1)?;
+        }
+        msg!("Staked {} NFTs At {}", entries.len(), timestamp);
         let global_authority = &mut ctx.accounts.global_authority;
-        require!(ctx.accounts.owner.key() == global_authority.super_admin, StakingError::InvalidSuperOwner);
-
-        let token_program = &mut &ctx.accounts.token_program;
-        let seeds = &[GLOBAL_AUTHORITY_SEED.as_bytes(), &[bump]];
-        let signer = &[&seeds[..]];
+        global_authority.total_staked_count += entries.len() as u64;
+
+        // Same check as stake_nft_to_pool, once for the whole batch
+        require!(
+            global_authority.is_solvent(token_account(&ctx.accounts.reward_vault)?.amount, timestamp),
+            StakingError::RewardVaultInsolvent
+        );
+
+        Ok(())
+    }
+
+    #[access_control(
+        user(&ctx.accounts.user_pool, &ctx.accounts.owner, &ctx.accounts.global_authority)
+        not_paused(&ctx.accounts.global_authority, PAUSE_UNSTAKING)
+    )]
+    pub fn withdraw_nfts_batch<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawNftsBatch<'info>>,
+        global_bump: u8,
+    ) -> Result<()> {
+        // Every stream's payout accounts come first, followed by the NFTs
+        let payout_len = ctx.accounts.global_authority.stream_count as usize * STREAM_PAYOUT_ACCOUNTS;
+        require!(ctx.remaining_accounts.len() >= payout_len, StakingError::InvalidRewardStreams);
+        let (payout_infos, nft_infos) = ctx.remaining_accounts.split_at(payout_len);
+        let owner = ctx.accounts.owner.key();
+        let pool = ctx.accounts.global_authority.key();
+        let payouts = load_stream_payouts(payout_infos, &ctx.accounts.global_authority, &owner)?;
+        let streams: Vec<&RewardStream> = payouts.iter().map(|payout| &*payout.stream).collect();
+        let entries = load_withdraw_batch(nft_infos, &owner, &pool)?;
+
+        let mut user_pool = UserPoolMut::load(ctx.accounts.user_pool.as_ref())?;
+        let timestamp = Clock::get()?.unix_timestamp;
+        let pool_seed = pool_id_seed(ctx.accounts.global_authority.pool_id);
+        let seeds = &[GLOBAL_AUTHORITY_SEED.as_bytes(), pool_seed.as_slice(), &[global_bump]];
+        let signer = &[&seeds[..]];
+        let owner_info = ctx.accounts.owner.to_account_info();
+        let token_program = ctx.accounts.token_program.to_account_info();
+
+        let mut reward: u64 = 0;
+        let mut penalty: u64 = 0;
+        let mut stream_rewards = [0u64; MAX_REWARD_STREAMS];
+        for entry in entries.iter() {
+            msg!("Staked Mint: {:?}", entry.nft_mint);
+            let stake_receipt = Account::<StakeReceipt>::try_from(&entry.stake_receipt)?;
+            require!(
+                stake_receipt.owner == owner && stake_receipt.pool == pool,
+                StakingError::InvalidStakeReceipt
+            );
+            let mut last_receipt = Account::<StakeReceipt>::try_from(&entry.last_receipt)?;
+            let (nft_reward, nft_penalty, nft_stream_rewards) = user_pool.remove_nft(
+                &stake_receipt,
+                &mut last_receipt,
+                timestamp,
+                &mut ctx.accounts.global_authority,
+                &streams,
+            )?;
+            // Store the moved entry's new index before the withdrawn NFT's receipt, possibly the same account, is closed
+            last_receipt.exit(&crate::ID)?;
+            stake_receipt.close(owner_info.clone())?;
+            reward += nft_reward;
+            penalty += nft_penalty;
+            for (total, nft_stream_reward) in stream_rewards.iter_mut().zip(nft_stream_rewards) {
+                *total += nft_stream_reward;
+            }
+
+            let authority = ctx.accounts.global_authority.to_account_info();
+            let cpi_accounts = Transfer {
+                from: entry.dest_nft_token_account.clone(),
+                to: entry.user_nft_token_account.clone(),
+                authority: authority.clone(),
+            };
+            token::transfer(CpiContext::new_with_signer(token_program.clone(), cpi_accounts, signer), 1)?;
+            invoke_signed(
+                &spl_token::instruction::close_account(
+                    token_program.key,
+                    entry.dest_nft_token_account.key,
+                    owner_info.key,
+                    authority.key,
+                    &[],
+                )?,
+                &[token_program.clone(), entry.dest_nft_token_account.clone(), owner_info.clone(), authority],
+                signer,
+            )?;
+        }
+        msg!("Reward: {:?} Penalty: {:?} Unstaked Time: {}", reward, penalty, timestamp);
+        ctx.accounts.global_authority.total_staked_count -= entries.len() as u64;
+
+        // Rewards of the whole batch are paid out in one transfer per token
+        let (credited, unpaid) = pay_reward(
+            &mut ctx.accounts.global_authority,
+            &ctx.accounts.reward_token_program,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.user_reward_account,
+            signer,
+            reward,
+        )?;
+        ctx.accounts.user_vault.credit(credited)?;
+        user_pool.owe_reward(unpaid, &mut ctx.accounts.global_authority);
+
+        if penalty > 0
+            && ctx.accounts.global_authority.burn_penalty
+            && ctx.accounts.global_authority.reward_funding == REWARD_FUNDING_VAULT
+        {
+            burn_checked(
+                &ctx.accounts.reward_token_program,
+                &ctx.accounts.reward_vault,
+                &ctx.accounts.reward_mint,
+                &ctx.accounts.global_authority.to_account_info(),
+                signer,
+                penalty,
+            )?;
+        }
+
+        for (index, payout) in payouts.iter().enumerate() {
+            let authority = ctx.accounts.global_authority.to_account_info();
+            if !pay_stream(payout, &authority, signer, stream_rewards[index])? {
+                user_pool.stream_owed[index] += stream_rewards[index];
+            }
+        }
+
+        Ok(())
+    }
+
+    #[access_control(user(&ctx.accounts.user_pool, &ctx.accounts.owner, &ctx.accounts.global_authority))]
+    pub fn emergency_withdraw_nft(
+        ctx: Context<EmergencyWithdrawNft>,
+        global_bump: u8,
+    ) -> Result<()> {
+        require!(ctx.accounts.global_authority.emergency, StakingError::EmergencyNotEnabled);
+
+        let mut user_pool = UserPoolMut::load(ctx.accounts.user_pool.as_ref())?;
+        msg!("Staked Mint: {:?}", ctx.accounts.nft_mint.key());
+        user_pool.emergency_remove_nft(
+            &ctx.accounts.stake_receipt,
+            &mut ctx.accounts.last_receipt,
+            &mut ctx.accounts.global_authority,
+        )?;
+        ctx.accounts.global_authority.total_staked_count -= 1;
+
+        let token_account_info = &mut &ctx.accounts.user_nft_token_account;
+        let dest_token_account_info = &mut &ctx.accounts.dest_nft_token_account;
+        let token_program = &mut &ctx.accounts.token_program;
+        let pool_seed = pool_id_seed(ctx.accounts.global_authority.pool_id);
+        let seeds = &[GLOBAL_AUTHORITY_SEED.as_bytes(), pool_seed.as_slice(), &[global_bump]];
+        let signer = &[&seeds[..]];
 
         let cpi_accounts = Transfer {
-            from: ctx.accounts.reward_vault.to_account_info(),
-            to: ctx.accounts.user_reward_account.to_account_info(),
-            authority: ctx.accounts.global_authority.to_account_info(),
+            from: dest_token_account_info.to_account_info().clone(),
+            to: token_account_info.to_account_info().clone(),
+            authority: ctx.accounts.global_authority.to_account_info()
         };
         token::transfer(
-            CpiContext::new_with_signer(
-                token_program.to_account_info().clone(),
-                cpi_accounts,
-                signer,
-            ),
+            CpiContext::new_with_signer(token_program.clone().to_account_info(), cpi_accounts, signer),
+            1
+        )?;
+
+        invoke_signed(
+            &spl_token::instruction::close_account(
+                token_program.key,
+                &dest_token_account_info.key(),
+                ctx.accounts.owner.key,
+                &ctx.accounts.global_authority.key(),
+                &[],
+            )?,
+            &[
+                token_program.clone().to_account_info(),
+                dest_token_account_info.to_account_info().clone(),
+                ctx.accounts.owner.to_account_info().clone(),
+                ctx.accounts.global_authority.to_account_info().clone(),
+            ],
+            signer,
+        )?;
+
+        emit!(EmergencyWithdrawn {
+            pool: ctx.accounts.global_authority.key(),
+            owner: ctx.accounts.owner.key(),
+            mint: ctx.accounts.nft_mint.key(),
+        });
+        Ok(())
+    }
+
+    #[access_control(
+        user(&ctx.accounts.user_pool, &ctx.accounts.owner, &ctx.accounts.global_authority)
+        not_paused(&ctx.accounts.global_authority, PAUSE_UNSTAKING)
+    )]
+    pub fn claim_rewards<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClaimRewards<'info>>,
+        global_bump: u8,
+    ) -> Result<()> {
+        let mut user_pool = UserPoolMut::load(ctx.accounts.user_pool.as_ref())?;
+        let payouts = load_stream_payouts(ctx.remaining_accounts, &ctx.accounts.global_authority, ctx.accounts.owner.key)?;
+
+        let timestamp = Clock::get()?.unix_timestamp;
+        let reward: u64 = user_pool.claim_rewards(timestamp, &mut ctx.accounts.global_authority)?;
+        msg!("Reward: {:?} Claimed Time: {}", reward, timestamp);
+
+        let pool_seed = pool_id_seed(ctx.accounts.global_authority.pool_id);
+        let seeds = &[GLOBAL_AUTHORITY_SEED.as_bytes(), pool_seed.as_slice(), &[global_bump]];
+        let signer = &[&seeds[..]];
+
+        let (credited, unpaid) = pay_reward(
+            &mut ctx.accounts.global_authority,
+            &ctx.accounts.token_program,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.user_reward_account,
+            signer,
+            reward,
+        )?;
+        ctx.accounts.user_vault.credit(credited)?;
+        user_pool.owe_reward(unpaid, &mut ctx.accounts.global_authority);
+
+        for (index, payout) in payouts.iter().enumerate() {
+            let stream_reward = user_pool.claim_stream(&payout.stream, timestamp, &ctx.accounts.global_authority)?;
+            let authority = ctx.accounts.global_authority.to_account_info();
+            if !pay_stream(payout, &authority, signer, stream_reward)? {
+                user_pool.stream_owed[index] = stream_reward;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[access_control(
+        has_role(&ctx.accounts.global_authority, &ctx.accounts.owner, ROLE_TREASURER)
+        not_paused(&ctx.accounts.global_authority, PAUSE_VAULT)
+    )]
+    pub fn withdraw_token(
+        ctx: Context<WithdrawToken>,
+        bump: u8,
+        amount: u64,
+    ) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+        require!(amount <= global_authority.withdraw_threshold, StakingError::WithdrawalRequiresTimelock);
+        // Funds backing staker rewards stay in the vault
+        let timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            global_authority.free_reward_balance(token_account(&ctx.accounts.reward_vault)?.amount, timestamp) >= amount,
+            StakingError::RewardVaultInsolvent
+        );
+
+        let pool_seed = pool_id_seed(ctx.accounts.global_authority.pool_id);
+        let seeds = &[GLOBAL_AUTHORITY_SEED.as_bytes(), pool_seed.as_slice(), &[bump]];
+        let signer = &[&seeds[..]];
+
+        transfer_checked(
+            &ctx.accounts.token_program,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.user_reward_account,
+            &ctx.accounts.global_authority.to_account_info(),
+            signer,
+            amount,
+        )?;
+        Ok(())
+    }
+
+    pub fn set_withdraw_timelock(
+        ctx: Context<ManageGlobalPool>,
+        threshold: u64,
+        delay: i64,
+    ) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+        require!(ctx.accounts.admin.key() == global_authority.super_admin, StakingError::InvalidSuperOwner);
+
+        let effective_after = global_authority.set_withdraw_timelock(threshold, delay, Clock::get()?.unix_timestamp)?;
+        msg!("Withdraw Threshold: {} Delay: {} Effective After: {}", threshold, delay, effective_after);
+        emit!(WithdrawTimelockUpdated {
+            pool: global_authority.key(),
+            admin: ctx.accounts.admin.key(),
+            threshold,
+            delay,
+            effective_after,
+        });
+        Ok(())
+    }
+
+    // Apply a loosened withdrawal timelock once the previous delay has passed
+    pub fn apply_withdraw_timelock(ctx: Context<ManageGlobalPool>) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+        require!(ctx.accounts.admin.key() == global_authority.super_admin, StakingError::InvalidSuperOwner);
+
+        global_authority.apply_withdraw_timelock(Clock::get()?.unix_timestamp)?;
+        msg!("Withdraw Threshold: {} Delay: {}", global_authority.withdraw_threshold, global_authority.withdraw_delay);
+        Ok(())
+    }
+
+    #[access_control(has_role(&ctx.accounts.global_authority, &ctx.accounts.owner, ROLE_TREASURER))]
+    pub fn queue_withdrawal(
+        ctx: Context<QueueWithdrawal>,
+        amount: u64,
+    ) -> Result<()> {
+        let global_authority = &mut ctx.accounts.global_authority;
+        let pending_withdrawal = &mut ctx.accounts.pending_withdrawal;
+        let timestamp = Clock::get()?.unix_timestamp;
+
+        pending_withdrawal.id = global_authority.withdrawal_count;
+        pending_withdrawal.proposer = ctx.accounts.owner.key();
+        pending_withdrawal.destination = ctx.accounts.user_reward_account.key();
+        pending_withdrawal.amount = amount;
+        pending_withdrawal.execute_after = timestamp + global_authority.withdraw_delay;
+        global_authority.withdrawal_count += 1;
+
+        emit!(WithdrawalQueued {
+            pool: global_authority.key(),
+            id: pending_withdrawal.id,
+            proposer: pending_withdrawal.proposer,
+            destination: pending_withdrawal.destination,
+            amount,
+            execute_after: pending_withdrawal.execute_after,
+        });
+        Ok(())
+    }
+
+    #[access_control(has_role(&ctx.accounts.global_authority, &ctx.accounts.owner, ROLE_TREASURER))]
+    pub fn cancel_withdrawal(ctx: Context<CancelWithdrawal>) -> Result<()> {
+        emit!(WithdrawalCancelled {
+            pool: ctx.accounts.global_authority.key(),
+            id: ctx.accounts.pending_withdrawal.id,
+            admin: ctx.accounts.owner.key(),
+        });
+        Ok(())
+    }
+
+    #[access_control(
+        has_role(&ctx.accounts.global_authority, &ctx.accounts.owner, ROLE_TREASURER)
+        not_paused(&ctx.accounts.global_authority, PAUSE_VAULT)
+    )]
+    pub fn execute_withdrawal(
+        ctx: Context<ExecuteWithdrawal>,
+        bump: u8,
+    ) -> Result<()> {
+        let pending_withdrawal = &ctx.accounts.pending_withdrawal;
+        let amount = pending_withdrawal.amount;
+        let timestamp = Clock::get()?.unix_timestamp;
+        require!(timestamp >= pending_withdrawal.execute_after, StakingError::WithdrawalTimelocked);
+        require!(
+            ctx.accounts.global_authority.free_reward_balance(token_account(&ctx.accounts.reward_vault)?.amount, timestamp) >= amount,
+            StakingError::RewardVaultInsolvent
+        );
+
+        let pool_seed = pool_id_seed(ctx.accounts.global_authority.pool_id);
+        let seeds = &[GLOBAL_AUTHORITY_SEED.as_bytes(), pool_seed.as_slice(), &[bump]];
+        let signer = &[&seeds[..]];
+
+        transfer_checked(
+            &ctx.accounts.token_program,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.destination,
+            &ctx.accounts.global_authority.to_account_info(),
+            signer,
             amount,
         )?;
+
+        emit!(WithdrawalExecuted {
+            pool: ctx.accounts.global_authority.key(),
+            id: pending_withdrawal.id,
+            destination: pending_withdrawal.destination,
+            amount,
+        });
         Ok(())
     }
 
@@ -396,367 +1361,1158 @@ pub mod staking {
 
 
 #[derive(Accounts)]
-#[instruction(bump: u8)]
-pub struct Initialize<'info> {
+#[instruction(bump: u8, pool_id: u64)]
+pub struct CreatePool<'info> {
     #[account(mut)]
     pub admin: Signer<'info>,
     #[account(
         init,
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(pool_id).as_ref()],
+        bump,
+        space = GLOBAL_POOL_SIZE,
+        payer = admin
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+
+    #[account(
+        init,
+        seeds = [REWARD_CONFIG_SEED.as_ref(), global_authority.key().as_ref()],
+        bump,
+        space = 8 + 178,
+        payer = admin
+    )]
+    pub reward_config: Box<Account<'info, RewardConfig>>,
+
+    #[account(
+        init,
+        seeds = [COLLECTION_REGISTRY_SEED.as_ref(), global_authority.key().as_ref()],
+        bump,
+        space = 8 + 368,
+        payer = admin
+    )]
+    pub collection_registry: Box<Account<'info, CollectionRegistry>>,
+
+    #[account(constraint = token_mint(&reward_mint)?.is_initialized)]
+    /// CHECK: SPL Token or Token-2022 mint, parsed by token_interface
+    pub reward_mint: AccountInfo<'info>,
+
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::Staking>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(admin.key()) @ StakingError::InvalidUpgradeAuthority)]
+    pub program_data: Account<'info, ProgramData>,
+
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>
+}
+
+#[derive(Accounts)]
+pub struct MigrateGlobalPool<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    // Legacy state only exists for the original pool, see pool_id_seed
+    #[account(
+        mut,
         seeds = [GLOBAL_AUTHORITY_SEED.as_ref()],
         bump,
-        space = 8 + 48,
+    )]
+    /// CHECK: Still in the legacy layout, deserialized by the handler
+    pub global_authority: AccountInfo<'info>,
+
+    #[account(
+        init,
+        seeds = [REWARD_CONFIG_SEED.as_ref(), global_authority.key().as_ref()],
+        bump,
+        space = 8 + 178,
+        payer = admin
+    )]
+    pub reward_config: Box<Account<'info, RewardConfig>>,
+
+    #[account(
+        init,
+        seeds = [COLLECTION_REGISTRY_SEED.as_ref(), global_authority.key().as_ref()],
+        bump,
+        space = 8 + 368,
         payer = admin
     )]
-    pub global_authority: Account<'info, GlobalPool>,
+    pub collection_registry: Box<Account<'info, CollectionRegistry>>,
+
+    #[account(constraint = token_mint(&reward_mint)?.is_initialized)]
+    /// CHECK: SPL Token or Token-2022 mint, parsed by token_interface
+    pub reward_mint: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>
+}
+
+#[derive(Accounts)]
+pub struct MigrateUserPool<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        owner = crate::ID,
+    )]
+    /// CHECK: Still in the legacy layout, deserialized by the handler
+    pub user_pool: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateUserPoolToPda<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        owner = crate::ID,
+    )]
+    /// CHECK: Keypair user pool in the current layout, loaded by the handler
+    pub legacy_user_pool: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [USER_POOL_SEED.as_ref(), global_authority.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    /// CHECK: Created and filled in by the handler
+    pub user_pool: AccountInfo<'info>,
+
+    #[account(
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct IssueStakeReceipt<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [USER_POOL_SEED.as_ref(), global_authority.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_pool: AccountLoader<'info, UserPool>,
+
+    #[account(
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+
+    /// CHECK: Looked up among the user pool's staked entries
+    pub nft_mint: AccountInfo<'info>,
+
+    #[account(
+        init,
+        seeds = [STAKE_RECEIPT_SEED.as_ref(), nft_mint.key().as_ref()],
+        bump,
+        space = STAKE_RECEIPT_SIZE,
+        payer = owner
+    )]
+    pub stake_receipt: Box<Account<'info, StakeReceipt>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ManageGlobalPool<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+}
+
+#[derive(Accounts)]
+pub struct SetRewardFunding<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+
+    #[account(constraint = reward_mint.key() == global_authority.reward_mint)]
+    /// CHECK: Checked against the pool's reward mint
+    pub reward_mint: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AddRewardStream<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+
+    #[account(
+        init,
+        seeds = [REWARD_STREAM_SEED.as_ref(), global_authority.key().as_ref(), &[global_authority.stream_count as u8]],
+        bump,
+        space = 8 + 268,
+        payer = admin
+    )]
+    pub reward_stream: Box<Account<'info, RewardStream>>,
+
+    #[account(constraint = token_mint(&stream_mint)?.is_initialized)]
+    /// CHECK: SPL Token or Token-2022 mint, parsed by token_interface
+    pub stream_mint: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct ManageRewardStream<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+
+    #[account(
+        mut,
+        seeds = [REWARD_STREAM_SEED.as_ref(), global_authority.key().as_ref(), &[reward_stream.index]],
+        bump,
+    )]
+    pub reward_stream: Box<Account<'info, RewardStream>>,
+}
+
+#[derive(Accounts)]
+pub struct ManageRewardConfig<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+
+    #[account(
+        mut,
+        seeds = [REWARD_CONFIG_SEED.as_ref(), global_authority.key().as_ref()],
+        bump,
+    )]
+    pub reward_config: Box<Account<'info, RewardConfig>>,
+}
+
+#[derive(Accounts)]
+pub struct ManageCollectionRegistry<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+
+    #[account(
+        mut,
+        seeds = [COLLECTION_REGISTRY_SEED.as_ref(), global_authority.key().as_ref()],
+        bump,
+    )]
+    pub collection_registry: Box<Account<'info, CollectionRegistry>>,
+}
+
+#[derive(Accounts)]
+pub struct QuoteReward<'info> {
+    #[account(
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+
+    #[account(
+        seeds = [REWARD_CONFIG_SEED.as_ref(), global_authority.key().as_ref()],
+        bump,
+    )]
+    pub reward_config: Box<Account<'info, RewardConfig>>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeUserPool<'info> {
+    #[account(
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        init,
+        seeds = [USER_POOL_SEED.as_ref(), global_authority.key().as_ref(), owner.key().as_ref()],
+        bump,
+        space = USER_POOL_SIZE,
+        payer = owner
+    )]
+    pub user_pool: AccountLoader<'info, UserPool>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeUserVault<'info> {
+    #[account(
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+
+    #[account(
+        init,
+        seeds = [VAULT_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref(), owner.key().as_ref()],
+        bump,
+        space = 8 + 48,
+        payer = owner
+    )]
+    pub user_vault: Account<'info, UserVault>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct CloseUserPool<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [USER_POOL_SEED.as_ref(), global_authority.key().as_ref(), owner.key().as_ref()],
+        bump,
+        close = owner
+    )]
+    pub user_pool: AccountLoader<'info, UserPool>,
+
+    #[account(
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+}
+
+#[derive(Accounts)]
+pub struct CloseUserVault<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref(), owner.key().as_ref()],
+        bump,
+        close = owner
+    )]
+    pub user_vault: Account<'info, UserVault>,
+
+    #[account(
+        mut,
+        constraint = is_token_account_of(&user_token_account, &global_authority.reward_mint, &user_vault.key())?,
+    )]
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub user_token_account: AccountInfo<'info>,
+
+    #[account(constraint = reward_mint.key() == global_authority.reward_mint)]
+    /// CHECK: Checked against the pool's reward mint
+    pub reward_mint: AccountInfo<'info>,
+
+    #[account(constraint = token_program.key == reward_mint.owner @ StakingError::InvalidTokenProgram)]
+    /// CHECK: SPL Token or Token-2022, whichever owns the reward mint
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct DepositToAccount<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+    
+    #[account(
+        mut,
+        seeds = [VAULT_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_vault: Account<'info, UserVault>,
+    
+    #[account(
+        mut,
+        constraint = is_token_account_of(&user_token_account, &global_authority.reward_mint, owner.key)?,
+    )]
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub user_token_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = is_token_account_of(&dest_token_account, &global_authority.reward_mint, &user_vault.key())?,
+    )]
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub dest_token_account: AccountInfo<'info>,
+    
+    #[account(constraint = reward_mint.key() == global_authority.reward_mint)]
+    /// CHECK: Checked against the pool's reward mint
+    pub reward_mint: AccountInfo<'info>,
+
+    #[account(constraint = token_program.key == reward_mint.owner @ StakingError::InvalidTokenProgram)]
+    /// CHECK: SPL Token or Token-2022, whichever owns the reward mint
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct WithdrawFromAccount<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+    
+    #[account(
+        mut,
+        seeds = [VAULT_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_vault: Account<'info, UserVault>,
+    
+    #[account(
+        mut,
+        constraint = is_token_account_of(&user_token_account, &global_authority.reward_mint, &user_vault.key())?,
+    )]
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub user_token_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = is_token_account_of(&dest_token_account, &global_authority.reward_mint, owner.key)?,
+    )]
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub dest_token_account: AccountInfo<'info>,
+    
+    #[account(constraint = reward_mint.key() == global_authority.reward_mint)]
+    /// CHECK: Checked against the pool's reward mint
+    pub reward_mint: AccountInfo<'info>,
+
+    #[account(constraint = token_program.key == reward_mint.owner @ StakingError::InvalidTokenProgram)]
+    /// CHECK: SPL Token or Token-2022, whichever owns the reward mint
+    pub token_program: AccountInfo<'info>,
+}
+
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct DepositToVault<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+    
+    #[account(
+        mut,
+        seeds = [VAULT_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_vault: Account<'info, UserVault>,
+    
+    #[account(
+        mut,
+        constraint = is_token_account_of(&reward_vault, &global_authority.reward_mint, &global_authority.key())?,
+    )]
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub reward_vault: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = is_token_account_of(&user_token_account, &global_authority.reward_mint, &user_vault.key())?,
+    )]
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub user_token_account: AccountInfo<'info>,
+    
+    #[account(constraint = reward_mint.key() == global_authority.reward_mint)]
+    /// CHECK: Checked against the pool's reward mint
+    pub reward_mint: AccountInfo<'info>,
+
+    #[account(constraint = token_program.key == reward_mint.owner @ StakingError::InvalidTokenProgram)]
+    /// CHECK: SPL Token or Token-2022, whichever owns the reward mint
+    pub token_program: AccountInfo<'info>,
+}
+
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct WithdrawFromVault<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+    
+    #[account(
+        mut,
+        seeds = [VAULT_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_vault: Account<'info, UserVault>,
+    
+    #[account(
+        mut,
+        constraint = is_token_account_of(&reward_vault, &global_authority.reward_mint, &global_authority.key())?,
+    )]
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub reward_vault: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = is_token_account_of(&dest_token_account, &global_authority.reward_mint, &user_vault.key())?,
+    )]
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub dest_token_account: AccountInfo<'info>,
+    
+    #[account(constraint = reward_mint.key() == global_authority.reward_mint)]
+    /// CHECK: Checked against the pool's reward mint
+    pub reward_mint: AccountInfo<'info>,
+
+    #[account(constraint = token_program.key == reward_mint.owner @ StakingError::InvalidTokenProgram)]
+    /// CHECK: SPL Token or Token-2022, whichever owns the reward mint
+    pub token_program: AccountInfo<'info>,
+}
+
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct Fusion<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+
+    #[account(
+        seeds = [COLLECTION_REGISTRY_SEED.as_ref(), global_authority.key().as_ref()],
+        bump,
+    )]
+    pub collection_registry: Box<Account<'info, CollectionRegistry>>,
+    
+    #[account(mut)]
+    pub update_authority: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [VAULT_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_vault: Account<'info, UserVault>,
+    
+    #[account(
+        mut,
+        constraint = is_token_account_of(&reward_vault, &global_authority.reward_mint, &global_authority.key())?,
+    )]
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub reward_vault: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = is_token_account_of(&user_token_account, &global_authority.reward_mint, &user_vault.key())?,
+    )]
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub user_token_account: AccountInfo<'info>,
+
+    #[account(mut)]
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub nft_mint: AccountInfo<'info>,
+
+     // The first mint metadata
+     #[account(mut)]
+     /// CHECK: This is not dangerous because we don't read or write from this account
+     pub mint_metadata: AccountInfo<'info>,
+ 
+     // the token metadata program
+     #[account(constraint = token_metadata_program.key == &metaplex_token_metadata::ID)]
+     /// CHECK: This is not dangerous because we don't read or write from this account
+     pub token_metadata_program: AccountInfo<'info>,
+    
+    #[account(constraint = reward_mint.key() == global_authority.reward_mint)]
+    /// CHECK: Checked against the pool's reward mint
+    pub reward_mint: AccountInfo<'info>,
+
+    #[account(constraint = token_program.key == reward_mint.owner @ StakingError::InvalidTokenProgram)]
+    /// CHECK: SPL Token or Token-2022, whichever owns the reward mint
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct StakeNftToPool<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [USER_POOL_SEED.as_ref(), global_authority.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_pool: AccountLoader<'info, UserPool>,
+
+    #[account(
+        mut,
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+
+    #[account(
+        seeds = [REWARD_CONFIG_SEED.as_ref(), global_authority.key().as_ref()],
+        bump,
+    )]
+    pub reward_config: Box<Account<'info, RewardConfig>>,
+
+    #[account(
+        seeds = [COLLECTION_REGISTRY_SEED.as_ref(), global_authority.key().as_ref()],
+        bump,
+    )]
+    pub collection_registry: Box<Account<'info, CollectionRegistry>>,
+
+    #[account(
+        constraint = is_token_account_of(&reward_vault, &global_authority.reward_mint, &global_authority.key())?,
+    )]
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub reward_vault: AccountInfo<'info>,
+    
+    #[account(
+        mut,
+        constraint = user_nft_token_account.mint == nft_mint.key(),
+        constraint = user_nft_token_account.owner == *owner.key,
+        constraint = user_nft_token_account.amount == 1,
+    )]
+    pub user_nft_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = dest_nft_token_account.mint == nft_mint.key(),
+        constraint = dest_nft_token_account.owner == global_authority.key(),
+    )]
+    pub dest_nft_token_account: Account<'info, TokenAccount>,
     
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>
-}
-
-#[derive(Accounts)]
-pub struct InitializeUserPool<'info> {
-    #[account(zero)]
-    pub user_pool: AccountLoader<'info, UserPool>,
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub nft_mint: AccountInfo<'info>,
+    /// the mint metadata
+    #[account(
+        mut,
+        constraint = mint_metadata.owner == &metaplex_token_metadata::ID
+    )]
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub mint_metadata: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(constraint = token_metadata_program.key == &metaplex_token_metadata::ID)]
+    pub token_metadata_program: AccountInfo<'info>,
 
     #[account(
         init,
-        seeds = [VAULT_SEED.as_ref(), owner.key().as_ref()],
+        seeds = [STAKE_RECEIPT_SEED.as_ref(), nft_mint.key().as_ref()],
         bump,
-        space = 8 + 48,
+        space = STAKE_RECEIPT_SIZE,
         payer = owner
     )]
-    pub user_vault: Account<'info, UserVault>,
-
-    #[account(mut)]
-    pub owner: Signer<'info>,
-
+    pub stake_receipt: Box<Account<'info, StakeReceipt>>,
     pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
 #[instruction(bump: u8)]
-pub struct DepositToAccount<'info> {
+pub struct WithdrawNftFromPool<'info> {
     #[account(mut)]
     pub owner: Signer<'info>,
     
     #[account(
         mut,
-        seeds = [VAULT_SEED.as_ref(), owner.key().as_ref()],
+        seeds = [USER_POOL_SEED.as_ref(), global_authority.key().as_ref(), owner.key().as_ref()],
         bump,
     )]
-    pub user_vault: Account<'info, UserVault>,
+    pub user_pool: AccountLoader<'info, UserPool>,
+
+    #[account(
+        mut,
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+    
+    #[account(
+        mut,
+        constraint = user_nft_token_account.mint == nft_mint.key(),
+        constraint = user_nft_token_account.owner == *owner.key,
+    )]
+    pub user_nft_token_account: Account<'info, TokenAccount>,
     
     #[account(
         mut,
-        constraint = user_token_account.mint == REWARD_TOKEN_MINT_PUBKEY.parse::<Pubkey>().unwrap(),
-        constraint = user_token_account.owner == *owner.key,
+        constraint = dest_nft_token_account.mint == nft_mint.key(),
+        constraint = dest_nft_token_account.owner == global_authority.key(),
+        constraint = dest_nft_token_account.amount == 1,
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
+    pub dest_nft_token_account: Account<'info, TokenAccount>,
 
     #[account(
         mut,
-        constraint = dest_token_account.mint == REWARD_TOKEN_MINT_PUBKEY.parse::<Pubkey>().unwrap(),
-        constraint = dest_token_account.owner == user_vault.key(),
+        constraint = is_token_account_of(&reward_vault, &global_authority.reward_mint, &global_authority.key())?,
     )]
-    pub dest_token_account: Account<'info, TokenAccount>,
-    
-    pub token_program: Program<'info, Token>,
-}
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub reward_vault: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = reward_mint.key() == global_authority.reward_mint,
+    )]
+    /// CHECK: Checked against the pool's reward mint
+    pub reward_mint: AccountInfo<'info>,
 
-#[derive(Accounts)]
-#[instruction(bump: u8)]
-pub struct WithdrawFromAccount<'info> {
-    #[account(mut)]
-    pub owner: Signer<'info>,
-    
     #[account(
         mut,
-        seeds = [VAULT_SEED.as_ref(), owner.key().as_ref()],
+        seeds = [VAULT_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref(), owner.key().as_ref()],
         bump,
     )]
-    pub user_vault: Account<'info, UserVault>,
+    pub user_vault: Box<Account<'info, UserVault>>,
     
     #[account(
         mut,
-        constraint = user_token_account.mint == REWARD_TOKEN_MINT_PUBKEY.parse::<Pubkey>().unwrap(),
-        constraint = user_token_account.owner == user_vault.key(),
+        constraint = is_token_account_of(&user_reward_account, &global_authority.reward_mint, &user_vault.key())?,
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub user_reward_account: AccountInfo<'info>,
+    
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub nft_mint: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    #[account(constraint = reward_token_program.key == reward_mint.owner @ StakingError::InvalidTokenProgram)]
+    /// CHECK: SPL Token or Token-2022, whichever owns the reward mint
+    pub reward_token_program: AccountInfo<'info>,
+
+    // Receipt of the user pool's last entry, which moves into the unstaked NFT's place.
+    // The same account as stake_receipt when the NFT is the last entry.
+    #[account(mut)]
+    pub last_receipt: Box<Account<'info, StakeReceipt>>,
 
     #[account(
         mut,
-        constraint = dest_token_account.mint == REWARD_TOKEN_MINT_PUBKEY.parse::<Pubkey>().unwrap(),
-        constraint = dest_token_account.owner == *owner.key,
+        seeds = [STAKE_RECEIPT_SEED.as_ref(), nft_mint.key().as_ref()],
+        bump = stake_receipt.bump,
+        has_one = owner,
+        constraint = stake_receipt.pool == global_authority.key() @ StakingError::InvalidStakeReceipt,
+        close = owner
     )]
-    pub dest_token_account: Account<'info, TokenAccount>,
-    
-    pub token_program: Program<'info, Token>,
+    pub stake_receipt: Box<Account<'info, StakeReceipt>>,
 }
 
-
 #[derive(Accounts)]
-#[instruction(bump: u8)]
-pub struct DepositToVault<'info> {
+pub struct StakeNftsBatch<'info> {
     #[account(mut)]
     pub owner: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [USER_POOL_SEED.as_ref(), global_authority.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_pool: AccountLoader<'info, UserPool>,
 
     #[account(
         mut,
-        seeds = [GLOBAL_AUTHORITY_SEED.as_ref()],
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
         bump,
     )]
     pub global_authority: Box<Account<'info, GlobalPool>>,
-    
+
     #[account(
-        mut,
-        seeds = [VAULT_SEED.as_ref(), owner.key().as_ref()],
+        seeds = [REWARD_CONFIG_SEED.as_ref(), global_authority.key().as_ref()],
         bump,
     )]
-    pub user_vault: Account<'info, UserVault>,
-    
+    pub reward_config: Box<Account<'info, RewardConfig>>,
+
     #[account(
-        mut,
-        constraint = reward_vault.mint == REWARD_TOKEN_MINT_PUBKEY.parse::<Pubkey>().unwrap(),
-        constraint = reward_vault.owner == global_authority.key(),
+        seeds = [COLLECTION_REGISTRY_SEED.as_ref(), global_authority.key().as_ref()],
+        bump,
     )]
-    pub reward_vault: Box<Account<'info, TokenAccount>>,
+    pub collection_registry: Box<Account<'info, CollectionRegistry>>,
 
     #[account(
-        mut,
-        constraint = user_token_account.mint == REWARD_TOKEN_MINT_PUBKEY.parse::<Pubkey>().unwrap(),
-        constraint = user_token_account.owner == user_vault.key(),
+        constraint = is_token_account_of(&reward_vault, &global_authority.reward_mint, &global_authority.key())?,
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
-    
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub reward_vault: AccountInfo<'info>,
+
     pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
 }
 
-
 #[derive(Accounts)]
 #[instruction(bump: u8)]
-pub struct WithdrawFromVault<'info> {
+pub struct WithdrawNftsBatch<'info> {
     #[account(mut)]
     pub owner: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [USER_POOL_SEED.as_ref(), global_authority.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_pool: AccountLoader<'info, UserPool>,
 
     #[account(
         mut,
-        seeds = [GLOBAL_AUTHORITY_SEED.as_ref()],
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
         bump,
     )]
     pub global_authority: Box<Account<'info, GlobalPool>>,
-    
+
     #[account(
         mut,
-        seeds = [VAULT_SEED.as_ref(), owner.key().as_ref()],
-        bump,
+        constraint = is_token_account_of(&reward_vault, &global_authority.reward_mint, &global_authority.key())?,
     )]
-    pub user_vault: Account<'info, UserVault>,
-    
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub reward_vault: AccountInfo<'info>,
+
     #[account(
         mut,
-        constraint = reward_vault.mint == REWARD_TOKEN_MINT_PUBKEY.parse::<Pubkey>().unwrap(),
-        constraint = reward_vault.owner == global_authority.key(),
+        constraint = reward_mint.key() == global_authority.reward_mint,
     )]
-    pub reward_vault: Box<Account<'info, TokenAccount>>,
+    /// CHECK: Checked against the pool's reward mint
+    pub reward_mint: AccountInfo<'info>,
 
     #[account(
         mut,
-        constraint = dest_token_account.mint == REWARD_TOKEN_MINT_PUBKEY.parse::<Pubkey>().unwrap(),
-        constraint = dest_token_account.owner == user_vault.key(),
+        seeds = [VAULT_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref(), owner.key().as_ref()],
+        bump,
     )]
-    pub dest_token_account: Account<'info, TokenAccount>,
+    pub user_vault: Box<Account<'info, UserVault>>,
     
+    #[account(
+        mut,
+        constraint = is_token_account_of(&user_reward_account, &global_authority.reward_mint, &user_vault.key())?,
+    )]
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub user_reward_account: AccountInfo<'info>,
+
     pub token_program: Program<'info, Token>,
+    #[account(constraint = reward_token_program.key == reward_mint.owner @ StakingError::InvalidTokenProgram)]
+    /// CHECK: SPL Token or Token-2022, whichever owns the reward mint
+    pub reward_token_program: AccountInfo<'info>,
 }
 
-
 #[derive(Accounts)]
 #[instruction(bump: u8)]
-pub struct Fusion<'info> {
+pub struct EmergencyWithdrawNft<'info> {
     #[account(mut)]
     pub owner: Signer<'info>,
-
+    
     #[account(
         mut,
-        seeds = [GLOBAL_AUTHORITY_SEED.as_ref()],
+        seeds = [USER_POOL_SEED.as_ref(), global_authority.key().as_ref(), owner.key().as_ref()],
         bump,
     )]
-    pub global_authority: Box<Account<'info, GlobalPool>>,
-    
-    #[account(mut)]
-    pub update_authority: Signer<'info>,
-    
+    pub user_pool: AccountLoader<'info, UserPool>,
+
     #[account(
         mut,
-        seeds = [VAULT_SEED.as_ref(), owner.key().as_ref()],
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
         bump,
     )]
-    pub user_vault: Account<'info, UserVault>,
+    pub global_authority: Box<Account<'info, GlobalPool>>,
     
     #[account(
         mut,
-        constraint = reward_vault.mint == REWARD_TOKEN_MINT_PUBKEY.parse::<Pubkey>().unwrap(),
-        constraint = reward_vault.owner == global_authority.key(),
+        constraint = user_nft_token_account.mint == nft_mint.key(),
+        constraint = user_nft_token_account.owner == *owner.key,
     )]
-    pub reward_vault: Box<Account<'info, TokenAccount>>,
-
+    pub user_nft_token_account: Account<'info, TokenAccount>,
+    
     #[account(
         mut,
-        constraint = user_token_account.mint == REWARD_TOKEN_MINT_PUBKEY.parse::<Pubkey>().unwrap(),
-        constraint = user_token_account.owner == user_vault.key(),
+        constraint = dest_nft_token_account.mint == nft_mint.key(),
+        constraint = dest_nft_token_account.owner == global_authority.key(),
+        constraint = dest_nft_token_account.amount == 1,
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
-
-    #[account(mut)]
+    pub dest_nft_token_account: Account<'info, TokenAccount>,
+    
     /// CHECK: This is not dangerous because we don't read or write from this account
     pub nft_mint: AccountInfo<'info>,
-
-     // The first mint metadata
-     #[account(mut)]
-     /// CHECK: This is not dangerous because we don't read or write from this account
-     pub mint_metadata: AccountInfo<'info>,
- 
-     // the token metadata program
-     #[account(constraint = token_metadata_program.key == &metaplex_token_metadata::ID)]
-     /// CHECK: This is not dangerous because we don't read or write from this account
-     pub token_metadata_program: AccountInfo<'info>,
-    
     pub token_program: Program<'info, Token>,
+
+    // Receipt of the user pool's last entry, which moves into the unstaked NFT's place.
+    // The same account as stake_receipt when the NFT is the last entry.
+    #[account(mut)]
+    pub last_receipt: Box<Account<'info, StakeReceipt>>,
+
+    #[account(
+        mut,
+        seeds = [STAKE_RECEIPT_SEED.as_ref(), nft_mint.key().as_ref()],
+        bump = stake_receipt.bump,
+        has_one = owner,
+        constraint = stake_receipt.pool == global_authority.key() @ StakingError::InvalidStakeReceipt,
+        close = owner
+    )]
+    pub stake_receipt: Box<Account<'info, StakeReceipt>>,
 }
 
 #[derive(Accounts)]
 #[instruction(bump: u8)]
-pub struct StakeNftToPool<'info> {
+pub struct ClaimRewards<'info> {
     #[account(mut)]
     pub owner: Signer<'info>,
     
-    #[account(mut)]
+    #[account(
+        mut,
+        seeds = [USER_POOL_SEED.as_ref(), global_authority.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
     pub user_pool: AccountLoader<'info, UserPool>,
 
     #[account(
         mut,
-        seeds = [GLOBAL_AUTHORITY_SEED.as_ref()],
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
         bump,
     )]
     pub global_authority: Box<Account<'info, GlobalPool>>,
-    
+
     #[account(
         mut,
-        constraint = user_nft_token_account.mint == nft_mint.key(),
-        constraint = user_nft_token_account.owner == *owner.key,
-        constraint = user_nft_token_account.amount == 1,
+        constraint = is_token_account_of(&reward_vault, &global_authority.reward_mint, &global_authority.key())?,
     )]
-    pub user_nft_token_account: Account<'info, TokenAccount>,
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub reward_vault: AccountInfo<'info>,
 
     #[account(
         mut,
-        constraint = dest_nft_token_account.mint == nft_mint.key(),
-        constraint = dest_nft_token_account.owner == global_authority.key(),
+        seeds = [VAULT_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref(), owner.key().as_ref()],
+        bump,
     )]
-    pub dest_nft_token_account: Account<'info, TokenAccount>,
+    pub user_vault: Box<Account<'info, UserVault>>,
     
-    /// CHECK: This is not dangerous because we don't read or write from this account
-    pub nft_mint: AccountInfo<'info>,
-    /// the mint metadata
     #[account(
         mut,
-        constraint = mint_metadata.owner == &metaplex_token_metadata::ID
+        constraint = is_token_account_of(&user_reward_account, &global_authority.reward_mint, &user_vault.key())?,
     )]
-    /// CHECK: This is not dangerous because we don't read or write from this account
-    pub mint_metadata: AccountInfo<'info>,
-    pub token_program: Program<'info, Token>,
-    /// CHECK: This is not dangerous because we don't read or write from this account
-    #[account(constraint = token_metadata_program.key == &metaplex_token_metadata::ID)]
-    pub token_metadata_program: AccountInfo<'info>,
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub user_reward_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = reward_mint.key() == global_authority.reward_mint,
+    )]
+    /// CHECK: Checked against the pool's reward mint
+    pub reward_mint: AccountInfo<'info>,
+
+    #[account(constraint = token_program.key == reward_mint.owner @ StakingError::InvalidTokenProgram)]
+    /// CHECK: SPL Token or Token-2022, whichever owns the reward mint
+    pub token_program: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
 #[instruction(bump: u8)]
-pub struct WithdrawNftFromPool<'info> {
+pub struct WithdrawToken<'info> {
     #[account(mut)]
     pub owner: Signer<'info>,
-    
-    #[account(mut)]
-    pub user_pool: AccountLoader<'info, UserPool>,
-
     #[account(
         mut,
-        seeds = [GLOBAL_AUTHORITY_SEED.as_ref()],
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
         bump,
     )]
     pub global_authority: Box<Account<'info, GlobalPool>>,
-    
     #[account(
         mut,
-        constraint = user_nft_token_account.mint == nft_mint.key(),
-        constraint = user_nft_token_account.owner == *owner.key,
+        constraint = is_token_account_of(&reward_vault, &global_authority.reward_mint, &global_authority.key())?,
     )]
-    pub user_nft_token_account: Account<'info, TokenAccount>,
-    
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub reward_vault: AccountInfo<'info>,
     #[account(
         mut,
-        constraint = dest_nft_token_account.mint == nft_mint.key(),
-        constraint = dest_nft_token_account.owner == global_authority.key(),
-        constraint = dest_nft_token_account.amount == 1,
+        constraint = is_token_account_of(&user_reward_account, &global_authority.reward_mint, owner.key)?,
     )]
-    pub dest_nft_token_account: Account<'info, TokenAccount>,
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub user_reward_account: AccountInfo<'info>,
+    #[account(constraint = reward_mint.key() == global_authority.reward_mint)]
+    /// CHECK: Checked against the pool's reward mint
+    pub reward_mint: AccountInfo<'info>,
+
+    #[account(constraint = token_program.key == reward_mint.owner @ StakingError::InvalidTokenProgram)]
+    /// CHECK: SPL Token or Token-2022, whichever owns the reward mint
+    pub token_program: AccountInfo<'info>,
+}
 
+#[derive(Accounts)]
+pub struct QueueWithdrawal<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
     #[account(
         mut,
-        constraint = reward_vault.mint == REWARD_TOKEN_MINT_PUBKEY.parse::<Pubkey>().unwrap(),
-        constraint = reward_vault.owner == global_authority.key(),
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
+    #[account(
+        init,
+        seeds = [PENDING_WITHDRAWAL_SEED.as_ref(), global_authority.key().as_ref(), global_authority.withdrawal_count.to_le_bytes().as_ref()],
+        bump,
+        space = 8 + 88,
+        payer = owner
+    )]
+    pub pending_withdrawal: Box<Account<'info, PendingWithdrawal>>,
+    #[account(
+        constraint = is_token_account_of(&user_reward_account, &global_authority.reward_mint, owner.key)?,
     )]
-    pub reward_vault: Box<Account<'info, TokenAccount>>,
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub user_reward_account: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
 
+#[derive(Accounts)]
+pub struct CancelWithdrawal<'info> {
+    pub owner: Signer<'info>,
+    #[account(
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
+        bump,
+    )]
+    pub global_authority: Box<Account<'info, GlobalPool>>,
     #[account(
         mut,
-        seeds = [VAULT_SEED.as_ref(), owner.key().as_ref()],
+        seeds = [PENDING_WITHDRAWAL_SEED.as_ref(), global_authority.key().as_ref(), pending_withdrawal.id.to_le_bytes().as_ref()],
         bump,
+        close = proposer,
     )]
-    pub user_vault: Box<Account<'info, UserVault>>,
-    
+    pub pending_withdrawal: Box<Account<'info, PendingWithdrawal>>,
     #[account(
         mut,
-        constraint = user_reward_account.mint == REWARD_TOKEN_MINT_PUBKEY.parse::<Pubkey>().unwrap(),
-        constraint = user_reward_account.owner == user_vault.key(),
+        constraint = proposer.key() == pending_withdrawal.proposer,
     )]
-    pub user_reward_account: Box<Account<'info, TokenAccount>>,
-    
-    /// CHECK: This is not dangerous because we don't read or write from this account
-    pub nft_mint: AccountInfo<'info>,
-    pub token_program: Program<'info, Token>,
+    /// CHECK: Only receives the rent of the closed pending withdrawal
+    pub proposer: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
 #[instruction(bump: u8)]
-pub struct WithdrawToken<'info> {
-    #[account(mut)]
+pub struct ExecuteWithdrawal<'info> {
     pub owner: Signer<'info>,
     #[account(
         mut,
-        seeds = [GLOBAL_AUTHORITY_SEED.as_ref()],
+        seeds = [GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(global_authority.pool_id).as_ref()],
         bump,
     )]
     pub global_authority: Box<Account<'info, GlobalPool>>,
     #[account(
         mut,
-        constraint = reward_vault.mint == REWARD_TOKEN_MINT_PUBKEY.parse::<Pubkey>().unwrap(),
-        constraint = reward_vault.owner == global_authority.key(),
+        seeds = [PENDING_WITHDRAWAL_SEED.as_ref(), global_authority.key().as_ref(), pending_withdrawal.id.to_le_bytes().as_ref()],
+        bump,
+        close = proposer,
     )]
-    pub reward_vault: Box<Account<'info, TokenAccount>>,
+    pub pending_withdrawal: Box<Account<'info, PendingWithdrawal>>,
     #[account(
         mut,
-        constraint = user_reward_account.mint == REWARD_TOKEN_MINT_PUBKEY.parse::<Pubkey>().unwrap(),
-        constraint = user_reward_account.owner == *owner.key,
+        constraint = proposer.key() == pending_withdrawal.proposer,
     )]
-    pub user_reward_account: Box<Account<'info, TokenAccount>>,
-    pub token_program: Program<'info, Token>,
+    /// CHECK: Only receives the rent of the closed pending withdrawal
+    pub proposer: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = is_token_account_of(&reward_vault, &global_authority.reward_mint, &global_authority.key())?,
+    )]
+    /// CHECK: SPL Token or Token-2022 account, parsed by token_interface
+    pub reward_vault: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = destination.key() == pending_withdrawal.destination,
+    )]
+    /// CHECK: Validated as a reward token account when the withdrawal was queued
+    pub destination: AccountInfo<'info>,
+    #[account(constraint = reward_mint.key() == global_authority.reward_mint)]
+    /// CHECK: Checked against the pool's reward mint
+    pub reward_mint: AccountInfo<'info>,
+
+    #[account(constraint = token_program.key == reward_mint.owner @ StakingError::InvalidTokenProgram)]
+    /// CHECK: SPL Token or Token-2022, whichever owns the reward mint
+    pub token_program: AccountInfo<'info>,
 }
 
 // Access control modifiers
-fn user(pool_loader: &AccountLoader<UserPool>, user: &AccountInfo) -> Result<()> {
+fn user(pool_loader: &AccountLoader<UserPool>, user: &AccountInfo, global_pool: &Account<GlobalPool>) -> Result<()> {
+    // Older layouts have to go through migrate_user_pool first
+    require!(
+        is_user_pool_len(pool_loader.to_account_info().data_len()),
+        StakingError::UnsupportedAccountVersion
+    );
     let user_pool = pool_loader.load()?;
+    // Only the version byte tells a current layout apart from one of the same size
+    require!(
+        user_pool.version == USER_POOL_VERSION && global_pool.version == GLOBAL_POOL_VERSION,
+        StakingError::UnsupportedAccountVersion
+    );
     require!(user_pool.owner == *user.key, StakingError::InvalidUserPool);
+    require!(user_pool.pool == global_pool.key(), StakingError::InvalidUserPool);
+    Ok(())
+}
+
+fn has_role(global_pool: &GlobalPool, signer: &AccountInfo, role: u8) -> Result<()> {
+    require!(global_pool.has_role(signer.key, role), StakingError::MissingRole);
+    Ok(())
+}
+
+fn not_paused(global_pool: &GlobalPool, group: u8) -> Result<()> {
+    require!(global_pool.paused & group == 0, StakingError::ProgramPaused);
     Ok(())
 }
\ No newline at end of file
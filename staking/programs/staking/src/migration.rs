@@ -0,0 +1,238 @@
+use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
+
+use crate::account::*;
+use crate::constants::*;
+use crate::error::*;
+
+// Layouts deployed before accounts carried a version byte
+pub const LEGACY_GLOBAL_POOL_SIZE: usize = 8 + 48;
+pub const LEGACY_USER_POOL_SIZE: usize = 8 + 5640;
+const LEGACY_STAKED_DATA_SIZE: usize = 56;
+// Layouts before the user pool grew with realloc all held 100 entries, they keep that capacity
+const LEGACY_STAKE_MAX_COUNT: usize = 100;
+pub const MIGRATED_USER_POOL_SIZE: usize = USER_POOL_HEADER_SIZE + LEGACY_STAKE_MAX_COUNT * STAKED_DATA_SIZE;
+
+#[derive(AnchorDeserialize, Default, Debug)]
+pub struct LegacyGlobalPool {
+    pub super_admin: Pubkey,        // 32
+    pub total_staked_count: u64,    // 8
+}
+
+impl LegacyGlobalPool {
+    pub fn try_from_bytes(data: &[u8]) -> Result<Self> {
+        require!(
+            data.len() == LEGACY_GLOBAL_POOL_SIZE && data[..8] == GlobalPool::discriminator(),
+            StakingError::UnsupportedAccountVersion
+        );
+        Self::deserialize(&mut &data[8..]).map_err(|_| error!(StakingError::UnsupportedAccountVersion))
+    }
+
+    pub fn migrate(&self, reward_mint: Pubkey, now: i64) -> GlobalPool {
+        GlobalPool {
+            version: GLOBAL_POOL_VERSION,
+            super_admin: self.super_admin,
+            total_staked_count: self.total_staked_count,
+            rate_updated_time: now,
+            withdraw_delay: MIN_WITHDRAW_DELAY,
+            reward_mint,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(AnchorDeserialize, Default, Clone, Copy, PartialEq, Debug)]
+pub struct LegacyStakedData {
+    pub mint: Pubkey,               // 32
+    pub staked_time: i64,           // 8
+    pub lock_time: i64,             // 8
+    pub duration: i64,              // 8
+}
+
+impl LegacyStakedData {
+    // Legacy stakes were paid a lump sum for their duration on unstake
+    pub fn migrate(&self, reward: u64) -> StakedData {
+        StakedData {
+            mint: self.mint,
+            staked_time: self.staked_time,
+            lock_time: self.lock_time,
+            duration: self.duration,
+            reward,
+            last_claimed: self.staked_time,
+            reward_mode: REWARD_MODE_TIER,
+            ..Default::default()
+        }
+    }
+}
+
+/// Reward the program paid per duration before tiers moved into RewardConfig.
+/// Legacy stakes keep it whatever the configured tiers say now. The legacy program
+/// accepted any duration and paid nothing for those outside its table.
+pub fn legacy_tier_reward(duration: i64) -> u64 {
+    let ammo = match duration {
+        1 => 90,
+        5 => 540,
+        15 => 1620,
+        30 => 3150,
+        _ => 0,
+    };
+    ammo * AMMO_DECIMAL
+}
+
+#[derive(Default, Debug)]
+pub struct LegacyUserPool {
+    pub owner: Pubkey,
+    pub staking: Vec<LegacyStakedData>,
+}
+
+impl LegacyUserPool {
+    pub fn try_from_bytes(data: &[u8]) -> Result<Self> {
+        require!(
+            data.len() == LEGACY_USER_POOL_SIZE && data[..8] == UserPool::discriminator(),
+            StakingError::UnsupportedAccountVersion
+        );
+        let mut buf = &data[8..];
+        let owner = Pubkey::deserialize(&mut buf).map_err(|_| error!(StakingError::UnsupportedAccountVersion))?;
+        let staked_count = u64::deserialize(&mut buf).map_err(|_| error!(StakingError::UnsupportedAccountVersion))? as usize;
+        require!(staked_count <= LEGACY_STAKE_MAX_COUNT, StakingError::UnsupportedAccountVersion);
+
+        let staking = buf
+            .chunks(LEGACY_STAKED_DATA_SIZE)
+            .take(staked_count)
+            .map(|mut chunk| LegacyStakedData::deserialize(&mut chunk))
+            .collect::<std::io::Result<Vec<_>>>()
+            .map_err(|_| error!(StakingError::UnsupportedAccountVersion))?;
+        Ok(Self { owner, staking })
+    }
+
+    // Rewrite the stakes into a current user pool of `pool`, committing their rewards to it
+    pub fn migrate(&self, user_pool: &mut UserPoolMut, global_pool: &mut GlobalPool, pool: Pubkey) {
+        user_pool.version = USER_POOL_VERSION;
+        user_pool.owner = self.owner;
+        user_pool.pool = pool;
+        for (idx, staked) in self.staking.iter().enumerate() {
+            user_pool.staking[idx] = staked.migrate(legacy_tier_reward(staked.duration));
+            global_pool.commit_stake(&user_pool.staking[idx]);
+        }
+        user_pool.staked_count = self.staking.len() as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Accounts dumped in the layout the program shipped with
+    const GLOBAL_POOL_FIXTURE: &[u8] = include_bytes!("../fixtures/global_pool_v0.bin");
+    const USER_POOL_FIXTURE: &[u8] = include_bytes!("../fixtures/user_pool_v0.bin");
+
+    #[test]
+    fn loads_legacy_global_pool() {
+        let legacy = LegacyGlobalPool::try_from_bytes(GLOBAL_POOL_FIXTURE).unwrap();
+        assert_eq!(legacy.super_admin, Pubkey::new(&[1; 32]));
+        assert_eq!(legacy.total_staked_count, 3);
+
+        let reward_mint = Pubkey::new_unique();
+        let global_pool = legacy.migrate(reward_mint, 1_700_000_000);
+        assert_eq!(global_pool.version, GLOBAL_POOL_VERSION);
+        assert_eq!(global_pool.super_admin, legacy.super_admin);
+        assert_eq!(global_pool.total_staked_count, 3);
+        assert_eq!(global_pool.reward_mint, reward_mint);
+        assert_eq!(global_pool.rate_updated_time, 1_700_000_000);
+        assert_eq!(global_pool.reward_liability, 0);
+        assert_eq!((global_pool.withdraw_threshold, global_pool.withdraw_delay), (0, MIN_WITHDRAW_DELAY));
+    }
+
+    #[test]
+    fn migrated_global_pool_fits_its_space() {
+        let legacy = LegacyGlobalPool::try_from_bytes(GLOBAL_POOL_FIXTURE).unwrap();
+        let mut data = Vec::new();
+        legacy.migrate(Pubkey::new_unique(), 0).try_serialize(&mut data).unwrap();
+        assert!(data.len() <= GLOBAL_POOL_SIZE);
+        assert_eq!(data[..8], GlobalPool::discriminator());
+    }
+
+    #[test]
+    fn loads_legacy_user_pool() {
+        let legacy = LegacyUserPool::try_from_bytes(USER_POOL_FIXTURE).unwrap();
+        assert_eq!(legacy.owner, Pubkey::new(&[2; 32]));
+        assert_eq!(legacy.staking.len(), 3);
+
+        let durations: Vec<i64> = legacy.staking.iter().map(|staked| staked.duration).collect();
+        assert_eq!(durations, vec![1, 15, 30]);
+        for (i, staked) in legacy.staking.iter().enumerate() {
+            assert_eq!(staked.mint, Pubkey::new(&[10 + i as u8; 32]));
+            assert_eq!(staked.lock_time, staked.staked_time + staked.duration * DAY);
+        }
+    }
+
+    #[test]
+    fn migrates_legacy_stakes_as_tier_rewards() {
+        let legacy = LegacyUserPool::try_from_bytes(USER_POOL_FIXTURE).unwrap();
+        let mut global_pool = GlobalPool::default();
+        for staked in legacy.staking.iter() {
+            let migrated = staked.migrate(legacy_tier_reward(staked.duration));
+            assert_eq!({ migrated.mint }, staked.mint);
+            assert_eq!({ migrated.lock_time }, staked.lock_time);
+            assert_eq!({ migrated.last_claimed }, staked.staked_time);
+            assert_eq!({ migrated.reward_mode }, REWARD_MODE_TIER);
+            global_pool.commit_stake(&migrated);
+        }
+        assert_eq!(global_pool.reward_liability, (90 + 1620 + 3150) * AMMO_DECIMAL);
+    }
+
+    #[test]
+    fn legacy_rewards_follow_the_original_table() {
+        assert_eq!(legacy_tier_reward(5), 540 * AMMO_DECIMAL);
+        assert_eq!(legacy_tier_reward(30), 3150 * AMMO_DECIMAL);
+    }
+
+    #[test]
+    fn migrates_legacy_stakes_of_unlisted_durations_without_reward() {
+        // The second stake was made for 7 days, which the legacy table didn't pay for
+        let mut fixture = USER_POOL_FIXTURE.to_vec();
+        let duration = 8 + 32 + 8 + LEGACY_STAKED_DATA_SIZE + 48;
+        fixture[duration..duration + 8].copy_from_slice(&7i64.to_le_bytes());
+        let legacy = LegacyUserPool::try_from_bytes(&fixture).unwrap();
+
+        let mut data = vec![0u8; MIGRATED_USER_POOL_SIZE];
+        data[..8].copy_from_slice(&UserPool::discriminator());
+        let (key, mut lamports) = (Pubkey::new_unique(), 0);
+        let info = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &crate::ID, false, 0);
+        let mut user_pool = UserPoolMut::load(&info).unwrap();
+        let (pool, mut global_pool) = (Pubkey::new_unique(), GlobalPool::default());
+        legacy.migrate(&mut user_pool, &mut global_pool, pool);
+
+        assert_eq!((user_pool.version, user_pool.owner, user_pool.pool), (USER_POOL_VERSION, legacy.owner, pool));
+        assert_eq!({ user_pool.staked_count }, 3);
+        let rewards: Vec<u64> = (0..3).map(|idx| user_pool.staking[idx].reward).collect();
+        assert_eq!(rewards, vec![90 * AMMO_DECIMAL, 0, 3150 * AMMO_DECIMAL]);
+        assert_eq!({ user_pool.staking[1].duration }, 7);
+        assert_eq!(global_pool.reward_liability, (90 + 3150) * AMMO_DECIMAL);
+    }
+
+    #[test]
+    fn rejects_current_layouts() {
+        let mut data = vec![0u8; GLOBAL_POOL_SIZE];
+        data[..8].copy_from_slice(&GlobalPool::discriminator());
+        assert!(LegacyGlobalPool::try_from_bytes(&data).is_err());
+
+        let mut data = vec![0u8; USER_POOL_SIZE];
+        data[..8].copy_from_slice(&UserPool::discriminator());
+        assert!(LegacyUserPool::try_from_bytes(&data).is_err());
+
+        let mut data = USER_POOL_FIXTURE.to_vec();
+        data[..8].copy_from_slice(&GlobalPool::discriminator());
+        assert!(LegacyUserPool::try_from_bytes(&data).is_err());
+    }
+
+    #[test]
+    fn current_layouts_reject_legacy_accounts() {
+        assert!(GlobalPool::try_deserialize(&mut &GLOBAL_POOL_FIXTURE[..]).is_err());
+
+        let mut data = USER_POOL_FIXTURE.to_vec();
+        let (key, mut lamports) = (Pubkey::new_unique(), 0);
+        let info = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &crate::ID, false, 0);
+        assert!(UserPoolMut::load(&info).is_err());
+    }
+}
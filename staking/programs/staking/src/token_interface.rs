@@ -0,0 +1,276 @@
+use anchor_lang::prelude::*;
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    program::invoke_signed,
+    program_pack::{IsInitialized, Pack},
+};
+use spl_token::{
+    instruction::TokenInstruction,
+    state::{Account as TokenAccount, Mint},
+};
+
+use crate::error::*;
+
+pub mod token_2022 {
+    anchor_lang::declare_id!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+}
+
+/// Token-2022 tags extended accounts with their type right after the SPL Token account layout
+const ACCOUNT_TYPE_OFFSET: usize = TokenAccount::LEN;
+const ACCOUNT_TYPE_MINT: u8 = 1;
+const ACCOUNT_TYPE_ACCOUNT: u8 = 2;
+
+pub fn is_token_program(key: &Pubkey) -> bool {
+    *key == spl_token::ID || *key == token_2022::ID
+}
+
+/// Parse the base state of a token account owned by SPL Token or Token-2022.
+/// Token-2022 keeps the SPL Token layout and appends its extensions after it.
+pub fn token_account(info: &AccountInfo) -> Result<TokenAccount> {
+    require!(is_token_program(info.owner), StakingError::InvalidTokenAccount);
+    unpack_token_account(&info.try_borrow_data()?)
+}
+
+/// Parse the base state of a mint owned by SPL Token or Token-2022
+pub fn token_mint(info: &AccountInfo) -> Result<Mint> {
+    require!(is_token_program(info.owner), StakingError::InvalidTokenMint);
+    unpack_mint(&info.try_borrow_data()?)
+}
+
+/// Whether `info` is a token account of `mint` held by `owner`
+pub fn is_token_account_of(info: &AccountInfo, mint: &Pubkey, owner: &Pubkey) -> Result<bool> {
+    let account = token_account(info)?;
+    Ok(account.mint == *mint && account.owner == *owner)
+}
+
+fn unpack_token_account(data: &[u8]) -> Result<TokenAccount> {
+    require!(data.len() >= TokenAccount::LEN, StakingError::InvalidTokenAccount);
+    if data.len() > ACCOUNT_TYPE_OFFSET {
+        require!(data[ACCOUNT_TYPE_OFFSET] == ACCOUNT_TYPE_ACCOUNT, StakingError::InvalidTokenAccount);
+    }
+    let account = TokenAccount::unpack_from_slice(&data[..TokenAccount::LEN])
+        .map_err(|_| error!(StakingError::InvalidTokenAccount))?;
+    require!(account.is_initialized(), StakingError::InvalidTokenAccount);
+    Ok(account)
+}
+
+fn unpack_mint(data: &[u8]) -> Result<Mint> {
+    require!(data.len() >= Mint::LEN, StakingError::InvalidTokenMint);
+    if data.len() > Mint::LEN {
+        require!(
+            data.len() > ACCOUNT_TYPE_OFFSET && data[ACCOUNT_TYPE_OFFSET] == ACCOUNT_TYPE_MINT,
+            StakingError::InvalidTokenMint
+        );
+    }
+    let mint = Mint::unpack_from_slice(&data[..Mint::LEN]).map_err(|_| error!(StakingError::InvalidTokenMint))?;
+    require!(mint.is_initialized, StakingError::InvalidTokenMint);
+    Ok(mint)
+}
+
+/// Move reward tokens with `transfer_checked` through the program owning the mint.
+/// Returns the amount actually credited to `to`, which is less than `amount`
+/// when a Token-2022 mint withholds a transfer fee.
+#[allow(clippy::too_many_arguments)]
+pub fn transfer_checked<'info>(
+    token_program: &AccountInfo<'info>,
+    from: &AccountInfo<'info>,
+    mint: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<u64> {
+    let decimals = token_mint(mint)?.decimals;
+    let balance_before = token_account(to)?.amount;
+
+    invoke_signed(
+        &Instruction {
+            program_id: *token_program.key,
+            accounts: vec![
+                AccountMeta::new(*from.key, false),
+                AccountMeta::new_readonly(*mint.key, false),
+                AccountMeta::new(*to.key, false),
+                AccountMeta::new_readonly(*authority.key, true),
+            ],
+            data: TokenInstruction::TransferChecked { amount, decimals }.pack(),
+        },
+        &[from.clone(), mint.clone(), to.clone(), authority.clone(), token_program.clone()],
+        signer_seeds,
+    )?;
+
+    credited_since(to, balance_before)
+}
+
+/// What `to` received since it held `balance_before`, net of any withheld transfer fee
+pub fn credited_since(to: &AccountInfo, balance_before: u64) -> Result<u64> {
+    token_account(to)?
+        .amount
+        .checked_sub(balance_before)
+        .ok_or_else(|| error!(StakingError::InvalidTokenAccount))
+}
+
+/// Burn reward tokens with `burn_checked` through the program owning the mint
+pub fn burn_checked<'info>(
+    token_program: &AccountInfo<'info>,
+    from: &AccountInfo<'info>,
+    mint: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<()> {
+    let decimals = token_mint(mint)?.decimals;
+
+    invoke_signed(
+        &Instruction {
+            program_id: *token_program.key,
+            accounts: vec![
+                AccountMeta::new(*from.key, false),
+                AccountMeta::new(*mint.key, false),
+                AccountMeta::new_readonly(*authority.key, true),
+            ],
+            data: TokenInstruction::BurnChecked { amount, decimals }.pack(),
+        },
+        &[from.clone(), mint.clone(), authority.clone(), token_program.clone()],
+        signer_seeds,
+    )?;
+    Ok(())
+}
+
+/// Mint reward tokens with `mint_to_checked` through the program owning the mint
+pub fn mint_to_checked<'info>(
+    token_program: &AccountInfo<'info>,
+    mint: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<()> {
+    let decimals = token_mint(mint)?.decimals;
+
+    invoke_signed(
+        &Instruction {
+            program_id: *token_program.key,
+            accounts: vec![
+                AccountMeta::new(*mint.key, false),
+                AccountMeta::new(*to.key, false),
+                AccountMeta::new_readonly(*authority.key, true),
+            ],
+            data: TokenInstruction::MintToChecked { amount, decimals }.pack(),
+        },
+        &[mint.clone(), to.clone(), authority.clone(), token_program.clone()],
+        signer_seeds,
+    )?;
+    Ok(())
+}
+
+/// Close an empty token account through the program owning it, returning its rent to `destination`
+pub fn close_account<'info>(
+    token_program: &AccountInfo<'info>,
+    account: &AccountInfo<'info>,
+    destination: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    invoke_signed(
+        &Instruction {
+            program_id: *token_program.key,
+            accounts: vec![
+                AccountMeta::new(*account.key, false),
+                AccountMeta::new(*destination.key, false),
+                AccountMeta::new_readonly(*authority.key, true),
+            ],
+            data: TokenInstruction::CloseAccount.pack(),
+        },
+        &[account.clone(), destination.clone(), authority.clone(), token_program.clone()],
+        signer_seeds,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::UserVault;
+    use solana_program::program_option::COption;
+    use spl_token::state::AccountState;
+
+    fn account_bytes(mint: Pubkey, owner: Pubkey, amount: u64) -> Vec<u8> {
+        let account = TokenAccount {
+            mint,
+            owner,
+            amount,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        };
+        let mut data = vec![0u8; TokenAccount::LEN];
+        account.pack_into_slice(&mut data);
+        data
+    }
+
+    fn mint_bytes(decimals: u8) -> Vec<u8> {
+        let mint = Mint {
+            mint_authority: COption::Some(Pubkey::new_unique()),
+            decimals,
+            is_initialized: true,
+            ..Mint::default()
+        };
+        let mut data = vec![0u8; Mint::LEN];
+        mint.pack_into_slice(&mut data);
+        data
+    }
+
+    #[test]
+    fn token_2022_accounts_parse_like_spl_token_accounts() {
+        let (mint, owner) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let data = account_bytes(mint, owner, 42);
+        assert_eq!(unpack_token_account(&data).unwrap().amount, 42);
+
+        // Extended account: type tag followed by e.g. a TransferFeeAmount extension
+        let mut extended = data.clone();
+        extended.push(ACCOUNT_TYPE_ACCOUNT);
+        extended.extend([0u8; 12]);
+        let account = unpack_token_account(&extended).unwrap();
+        assert_eq!((account.mint, account.owner, account.amount), (mint, owner, 42));
+
+        extended[ACCOUNT_TYPE_OFFSET] = ACCOUNT_TYPE_MINT;
+        assert!(unpack_token_account(&extended).is_err());
+        assert!(unpack_token_account(&data[..TokenAccount::LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn credits_what_is_left_after_a_transfer_fee() {
+        // 1000 sent to an account holding 100, the mint withheld a fee of 10
+        let (key, owner, mut lamports) = (Pubkey::new_unique(), Pubkey::new_unique(), 0);
+        let mut data = account_bytes(Pubkey::new_unique(), owner, 1090);
+        let info = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &spl_token::ID, false, 0);
+        let credited = credited_since(&info, 100).unwrap();
+        assert_eq!(credited, 990);
+
+        let mut user_vault = UserVault { owner, amount: 100 };
+        user_vault.credit(credited).unwrap();
+        assert_eq!(user_vault.amount, 1090);
+        assert!(user_vault.credit(u64::MAX).is_err());
+        assert!(user_vault.debit(1091).is_err());
+        assert_eq!(user_vault.amount, 1090);
+        user_vault.debit(1090).unwrap();
+        assert_eq!(user_vault.amount, 0);
+    }
+
+    #[test]
+    fn token_2022_mints_parse_like_spl_token_mints() {
+        let data = mint_bytes(9);
+        assert_eq!(unpack_mint(&data).unwrap().decimals, 9);
+
+        // Extended mints are padded up to the account length before their type tag
+        let mut extended = data.clone();
+        extended.resize(ACCOUNT_TYPE_OFFSET, 0);
+        extended.push(ACCOUNT_TYPE_MINT);
+        extended.extend([0u8; 108]);
+        assert_eq!(unpack_mint(&extended).unwrap().decimals, 9);
+
+        // A token account is never mistaken for a mint
+        let mut account = account_bytes(Pubkey::new_unique(), Pubkey::new_unique(), 1);
+        account.push(ACCOUNT_TYPE_ACCOUNT);
+        assert!(unpack_mint(&account).is_err());
+    }
+}
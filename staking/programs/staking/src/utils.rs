@@ -0,0 +1,489 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token::TokenAccount;
+use metaplex_token_metadata::state::{Creator, Key, Metadata, PREFIX};
+use solana_program::{
+    program::{invoke, invoke_signed},
+    system_instruction,
+};
+
+use crate::account::*;
+use crate::constants::*;
+use crate::error::*;
+use crate::token_interface::*;
+
+/// Collection field Metaplex appends to the metadata account after `edition_nonce`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Debug)]
+pub struct MetadataCollection {
+    pub verified: bool,
+    pub key: Pubkey,
+}
+
+/// Parse the metadata account along with its (optional) verified collection.
+/// Metadata created before collections existed is zero padded, so the trailing
+/// `token_standard` and `collection` fields both read as `None`.
+pub fn parse_metadata(data: &[u8]) -> Result<(Metadata, Option<MetadataCollection>)> {
+    require!(!data.is_empty() && data[0] == Key::MetadataV1 as u8, StakingError::InvalidMetadata);
+
+    let mut buf = data;
+    let metadata = Metadata::deserialize(&mut buf).map_err(|_| error!(StakingError::InvalidMetadata))?;
+    let _token_standard: Option<u8> = Option::deserialize(&mut buf).unwrap_or(None);
+    let collection: Option<MetadataCollection> = Option::deserialize(&mut buf).unwrap_or(None);
+    Ok((metadata, collection))
+}
+
+/// Find the registered collection an NFT belongs to, either through one of its
+/// verified creators or its verified Metaplex collection, depending on how the
+/// collection was registered. `allowed` selects stakeable or fusable entries.
+pub fn find_allowed_collection(
+    registry: &CollectionRegistry,
+    creators: Option<&Vec<Creator>>,
+    collection: Option<MetadataCollection>,
+    allowed: fn(&AllowedCollection) -> bool,
+) -> Result<Pubkey> {
+    if creators.is_none() && collection.is_none() {
+        return Err(error!(StakingError::MetadataCreatorParseError));
+    }
+
+    if let Some(creators) = creators {
+        for creator in creators.iter().filter(|creator| creator.verified) {
+            if let Some(entry) = registry.find(&creator.address) {
+                if entry.verify_creator && allowed(entry) {
+                    return Ok(creator.address);
+                }
+            }
+        }
+    }
+
+    if let Some(collection) = collection.filter(|collection| collection.verified) {
+        if let Some(entry) = registry.find(&collection.key) {
+            if entry.verify_collection && allowed(entry) {
+                return Ok(collection.key);
+            }
+        }
+    }
+
+    Err(error!(StakingError::UnkownOrNotAllowedNFTCollection))
+}
+
+/// Check an NFT's metadata account and find the registered collection it is stakeable under
+pub fn stakeable_collection(
+    nft_mint: &Pubkey,
+    mint_metadata: &AccountInfo,
+    registry: &CollectionRegistry,
+) -> Result<Pubkey> {
+    let (metadata, _) = Pubkey::find_program_address(
+        &[PREFIX.as_bytes(), metaplex_token_metadata::id().as_ref(), nft_mint.as_ref()],
+        &metaplex_token_metadata::id(),
+    );
+    require!(
+        metadata == *mint_metadata.key && *mint_metadata.owner == metaplex_token_metadata::ID,
+        StakingError::InvalidMetadata
+    );
+
+    // verify metadata is legit
+    let (nft_metadata, nft_collection) = parse_metadata(&mint_metadata.data.borrow())?;
+    find_allowed_collection(registry, nft_metadata.data.creators.as_ref(), nft_collection, |entry| entry.stakeable)
+}
+
+/// Id seed of a pool authority PDA. The original pool predates pool ids, so
+/// pool 0 has an empty id seed and keeps the address of the old global authority.
+pub fn pool_id_seed(pool_id: u64) -> Vec<u8> {
+    if pool_id == 0 {
+        return Vec::new();
+    }
+    pool_id.to_le_bytes().to_vec()
+}
+
+/// Seed the reward tiers and allowed collections of a freshly created pool
+pub fn configure_pool(
+    reward_config: &mut RewardConfig,
+    collection_registry: &mut CollectionRegistry,
+    collections: Vec<AllowedCollection>,
+    tiers: Vec<RewardTier>,
+) -> Result<()> {
+    for tier in tiers {
+        reward_config.add_tier(tier.duration, tier.reward)?;
+    }
+    for collection in collections {
+        require!(collection.verify_creator || collection.verify_collection, StakingError::InvalidCollectionGate);
+        collection_registry.add_collection(collection)?;
+    }
+    Ok(())
+}
+
+/// Resize a program owned account, topping up its rent exemption from `payer`
+pub fn realloc_account<'info>(
+    account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    new_len: usize,
+) -> Result<()> {
+    let minimum_balance = Rent::get()?.minimum_balance(new_len);
+    let lamports = minimum_balance.saturating_sub(account.lamports());
+    if lamports > 0 {
+        invoke(
+            &system_instruction::transfer(payer.key, account.key, lamports),
+            &[payer.clone(), account.clone(), system_program.clone()],
+        )?;
+    }
+    account.realloc(new_len, true)?;
+    Ok(())
+}
+
+/// Create a program owned PDA, also when someone already sent lamports to its address
+pub fn create_pda_account<'info>(
+    account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    space: usize,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let minimum_balance = Rent::get()?.minimum_balance(space);
+    let accounts = &[payer.clone(), account.clone(), system_program.clone()];
+    if account.lamports() == 0 {
+        invoke_signed(
+            &system_instruction::create_account(payer.key, account.key, minimum_balance, space as u64, &crate::ID),
+            accounts,
+            signer_seeds,
+        )?;
+        return Ok(());
+    }
+
+    let lamports = minimum_balance.saturating_sub(account.lamports());
+    if lamports > 0 {
+        invoke(&system_instruction::transfer(payer.key, account.key, lamports), accounts)?;
+    }
+    invoke_signed(&system_instruction::allocate(account.key, space as u64), accounts, signer_seeds)?;
+    invoke_signed(&system_instruction::assign(account.key, &crate::ID), accounts, signer_seeds)?;
+    Ok(())
+}
+
+/// Pay a staker's reward, minted under the emission cap or transferred from the reward vault.
+/// Returns the amount credited to `to`, transfers of a fee-bearing mint credit less, and
+/// the part of `amount` left unpaid because it didn't fit under the emission cap.
+#[allow(clippy::too_many_arguments)]
+pub fn pay_reward<'info>(
+    global_pool: &mut Account<'info, GlobalPool>,
+    token_program: &AccountInfo<'info>,
+    reward_vault: &AccountInfo<'info>,
+    reward_mint: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<(u64, u64)> {
+    if global_pool.reward_funding == REWARD_FUNDING_MINT {
+        let mintable = global_pool.record_emission(amount);
+        if mintable > 0 {
+            mint_to_checked(token_program, reward_mint, to, &global_pool.to_account_info(), signer_seeds, mintable)?;
+        }
+        return Ok((mintable, amount - mintable));
+    }
+    let credited = transfer_checked(token_program, reward_vault, reward_mint, to, &global_pool.to_account_info(), signer_seeds, amount)?;
+    Ok((credited, 0))
+}
+
+/// Reward streams of a pool, passed in index order through remaining_accounts
+pub fn load_reward_streams<'info>(
+    accounts: &[AccountInfo<'info>],
+    global_pool: &Account<GlobalPool>,
+) -> Result<Vec<Account<'info, RewardStream>>> {
+    require!(accounts.len() == global_pool.stream_count as usize, StakingError::InvalidRewardStreams);
+    let pool = *global_pool.to_account_info().key;
+    accounts
+        .iter()
+        .enumerate()
+        .map(|(index, info)| {
+            let stream = Account::<RewardStream>::try_from(info)?;
+            require!(
+                stream.pool == pool && stream.index as usize == index,
+                StakingError::InvalidRewardStreams
+            );
+            Ok(stream)
+        })
+        .collect()
+}
+
+/// Accounts paying out one reward stream
+pub struct StreamPayout<'info> {
+    pub stream: Account<'info, RewardStream>,
+    pub vault: AccountInfo<'info>,
+    pub mint: AccountInfo<'info>,
+    pub destination: AccountInfo<'info>,
+    pub token_program: AccountInfo<'info>,
+}
+
+/// Every reward stream of a pool with the accounts paying it to `owner`, passed through
+/// remaining_accounts as [stream, stream vault, stream mint, destination, token program]
+pub fn load_stream_payouts<'info>(
+    accounts: &[AccountInfo<'info>],
+    global_pool: &Account<GlobalPool>,
+    owner: &Pubkey,
+) -> Result<Vec<StreamPayout<'info>>> {
+    require!(
+        accounts.len() == global_pool.stream_count as usize * STREAM_PAYOUT_ACCOUNTS,
+        StakingError::InvalidRewardStreams
+    );
+    let pool = *global_pool.to_account_info().key;
+    let streams: Vec<AccountInfo<'info>> = accounts.iter().step_by(STREAM_PAYOUT_ACCOUNTS).cloned().collect();
+    load_reward_streams(&streams, global_pool)?
+        .into_iter()
+        .zip(accounts.chunks(STREAM_PAYOUT_ACCOUNTS))
+        .map(|(stream, group)| {
+            let (vault, mint, destination, token_program) = (&group[1], &group[2], &group[3], &group[4]);
+            require!(
+                *mint.key == stream.mint
+                    && token_program.key == mint.owner
+                    && is_token_account_of(vault, &stream.mint, &pool)?
+                    && is_token_account_of(destination, &stream.mint, owner)?,
+                StakingError::InvalidRewardStreams
+            );
+            Ok(StreamPayout {
+                stream,
+                vault: vault.clone(),
+                mint: mint.clone(),
+                destination: destination.clone(),
+                token_program: token_program.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Pay a stream reward from its vault. Returns false, leaving the reward owed,
+/// when the vault has run dry so the other streams still get paid.
+pub fn pay_stream<'info>(
+    payout: &StreamPayout<'info>,
+    authority: &AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<bool> {
+    if amount == 0 {
+        return Ok(true);
+    }
+    if token_account(&payout.vault)?.amount < amount {
+        msg!("Reward Stream {} Can't Pay {}", payout.stream.index, amount);
+        return Ok(false);
+    }
+    transfer_checked(
+        &payout.token_program,
+        &payout.vault,
+        &payout.mint,
+        &payout.destination,
+        authority,
+        signer_seeds,
+        amount,
+    )?;
+    Ok(true)
+}
+
+/// One NFT of stake_nfts_batch
+pub struct StakeBatchEntry<'info> {
+    pub nft_mint: Pubkey,
+    pub user_nft_token_account: AccountInfo<'info>,
+    pub dest_nft_token_account: AccountInfo<'info>,
+    pub stake_receipt: AccountInfo<'info>,
+    pub receipt_bump: u8,
+}
+
+/// NFTs to stake, checked like the accounts of stake_nft_to_pool and passed through
+/// remaining_accounts as [nft mint, mint metadata, user token account, pool token account, stake receipt]
+pub fn load_stake_batch<'info>(
+    accounts: &[AccountInfo<'info>],
+    owner: &Pubkey,
+    pool: &Pubkey,
+    registry: &CollectionRegistry,
+) -> Result<Vec<StakeBatchEntry<'info>>> {
+    let groups = accounts.chunks_exact(STAKE_BATCH_ACCOUNTS);
+    require!(groups.len() > 0 && groups.remainder().is_empty(), StakingError::InvalidBatchAccounts);
+    groups
+        .map(|group| {
+            let (nft_mint, mint_metadata, user_nft_token_account, dest_nft_token_account, stake_receipt) =
+                (&group[0], &group[1], &group[2], &group[3], &group[4]);
+            let collection = stakeable_collection(nft_mint.key, mint_metadata, registry)?;
+            msg!("Stake Mint: {:?} Collection: {:?}", nft_mint.key, collection);
+
+            let user_token = Account::<TokenAccount>::try_from(user_nft_token_account)?;
+            let dest_token = Account::<TokenAccount>::try_from(dest_nft_token_account)?;
+            require!(
+                user_token.mint == *nft_mint.key
+                    && user_token.owner == *owner
+                    && user_token.amount == 1
+                    && dest_token.mint == *nft_mint.key
+                    && dest_token.owner == *pool,
+                StakingError::InvalidBatchAccounts
+            );
+
+            let (receipt, receipt_bump) =
+                Pubkey::find_program_address(&[STAKE_RECEIPT_SEED.as_bytes(), nft_mint.key.as_ref()], &crate::ID);
+            require!(receipt == *stake_receipt.key, StakingError::InvalidStakeReceipt);
+            Ok(StakeBatchEntry {
+                nft_mint: *nft_mint.key,
+                user_nft_token_account: user_nft_token_account.clone(),
+                dest_nft_token_account: dest_nft_token_account.clone(),
+                stake_receipt: stake_receipt.clone(),
+                receipt_bump,
+            })
+        })
+        .collect()
+}
+
+/// One NFT of withdraw_nfts_batch. The receipts are only read once the NFTs before it
+/// are withdrawn, since withdrawing moves the last entry and updates its receipt.
+pub struct WithdrawBatchEntry<'info> {
+    pub nft_mint: Pubkey,
+    pub user_nft_token_account: AccountInfo<'info>,
+    pub dest_nft_token_account: AccountInfo<'info>,
+    pub stake_receipt: AccountInfo<'info>,
+    pub last_receipt: AccountInfo<'info>,
+}
+
+/// NFTs to withdraw, checked like the accounts of withdraw_nft_from_pool and passed through
+/// remaining_accounts as [nft mint, user token account, pool token account, stake receipt, last receipt].
+/// The last receipt belongs to the user pool's last entry after the NFTs before it are withdrawn.
+pub fn load_withdraw_batch<'info>(
+    accounts: &[AccountInfo<'info>],
+    owner: &Pubkey,
+    pool: &Pubkey,
+) -> Result<Vec<WithdrawBatchEntry<'info>>> {
+    let groups = accounts.chunks_exact(WITHDRAW_BATCH_ACCOUNTS);
+    require!(groups.len() > 0 && groups.remainder().is_empty(), StakingError::InvalidBatchAccounts);
+    groups
+        .map(|group| {
+            let (nft_mint, user_nft_token_account, dest_nft_token_account, stake_receipt, last_receipt) =
+                (&group[0], &group[1], &group[2], &group[3], &group[4]);
+            let user_token = Account::<TokenAccount>::try_from(user_nft_token_account)?;
+            let dest_token = Account::<TokenAccount>::try_from(dest_nft_token_account)?;
+            require!(
+                user_token.mint == *nft_mint.key
+                    && user_token.owner == *owner
+                    && dest_token.mint == *nft_mint.key
+                    && dest_token.owner == *pool
+                    && dest_token.amount == 1,
+                StakingError::InvalidBatchAccounts
+            );
+
+            let (receipt, _) =
+                Pubkey::find_program_address(&[STAKE_RECEIPT_SEED.as_bytes(), nft_mint.key.as_ref()], &crate::ID);
+            require!(receipt == *stake_receipt.key, StakingError::InvalidStakeReceipt);
+            Ok(WithdrawBatchEntry {
+                nft_mint: *nft_mint.key,
+                user_nft_token_account: user_nft_token_account.clone(),
+                dest_nft_token_account: dest_nft_token_account.clone(),
+                stake_receipt: stake_receipt.clone(),
+                last_receipt: last_receipt.clone(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::GLOBAL_AUTHORITY_SEED;
+    use metaplex_token_metadata::state::Data;
+
+    fn metadata_bytes(creators: Option<Vec<Creator>>, collection: Option<MetadataCollection>) -> Vec<u8> {
+        let metadata = Metadata {
+            key: Key::MetadataV1,
+            update_authority: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            data: Data {
+                name: "Solarmy #1".to_string(),
+                symbol: "SOLARMY".to_string(),
+                uri: "https://solarmy.io/1.json".to_string(),
+                seller_fee_basis_points: 500,
+                creators,
+            },
+            primary_sale_happened: true,
+            is_mutable: true,
+            edition_nonce: Some(255),
+        };
+        let mut data = metadata.try_to_vec().unwrap();
+        if collection.is_some() {
+            data.extend(Some(0u8).try_to_vec().unwrap());
+            data.extend(collection.try_to_vec().unwrap());
+        }
+        // Metadata accounts are allocated at their maximum length
+        data.resize(679, 0);
+        data
+    }
+
+    fn registry(entry: AllowedCollection) -> CollectionRegistry {
+        let mut registry = CollectionRegistry::default();
+        registry.collections[0] = entry;
+        registry.collection_count = 1;
+        registry
+    }
+
+    fn allowed(address: Pubkey, verify_creator: bool, verify_collection: bool) -> AllowedCollection {
+        AllowedCollection {
+            address,
+            stakeable: true,
+            fusable: false,
+            verify_creator,
+            verify_collection,
+        }
+    }
+
+    fn check(registry: &CollectionRegistry, data: &[u8]) -> Result<Pubkey> {
+        let (metadata, collection) = parse_metadata(data)?;
+        find_allowed_collection(registry, metadata.data.creators.as_ref(), collection, |entry| entry.stakeable)
+    }
+
+    #[test]
+    fn accepts_verified_creator() {
+        let address = Pubkey::new_unique();
+        let data = metadata_bytes(Some(vec![Creator { address, verified: true, share: 100 }]), None);
+        assert_eq!(check(&registry(allowed(address, true, false)), &data).unwrap(), address);
+    }
+
+    #[test]
+    fn rejects_unverified_creator() {
+        let address = Pubkey::new_unique();
+        let data = metadata_bytes(Some(vec![Creator { address, verified: false, share: 100 }]), None);
+        assert!(check(&registry(allowed(address, true, false)), &data).is_err());
+    }
+
+    #[test]
+    fn accepts_verified_collection() {
+        let key = Pubkey::new_unique();
+        let data = metadata_bytes(None, Some(MetadataCollection { verified: true, key }));
+        assert_eq!(check(&registry(allowed(key, false, true)), &data).unwrap(), key);
+    }
+
+    #[test]
+    fn rejects_unverified_collection() {
+        let key = Pubkey::new_unique();
+        let data = metadata_bytes(None, Some(MetadataCollection { verified: false, key }));
+        assert!(check(&registry(allowed(key, false, true)), &data).is_err());
+    }
+
+    #[test]
+    fn respects_verification_mode() {
+        let key = Pubkey::new_unique();
+        let creator_only = registry(allowed(key, true, false));
+        let data = metadata_bytes(None, Some(MetadataCollection { verified: true, key }));
+        assert!(check(&creator_only, &data).is_err());
+
+        let collection_only = registry(allowed(key, false, true));
+        let data = metadata_bytes(Some(vec![Creator { address: key, verified: true, share: 100 }]), None);
+        assert!(check(&collection_only, &data).is_err());
+    }
+
+    #[test]
+    fn original_pool_keeps_its_address() {
+        let (legacy, _) = Pubkey::find_program_address(&[GLOBAL_AUTHORITY_SEED.as_ref()], &crate::ID);
+        let (pool, _) = Pubkey::find_program_address(&[GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(0).as_ref()], &crate::ID);
+        assert_eq!(pool, legacy);
+
+        let (other, _) = Pubkey::find_program_address(&[GLOBAL_AUTHORITY_SEED.as_ref(), pool_id_seed(1).as_ref()], &crate::ID);
+        assert_ne!(other, legacy);
+    }
+
+    #[test]
+    fn rejects_metadata_without_creators_or_collection() {
+        let data = metadata_bytes(None, None);
+        let (metadata, collection) = parse_metadata(&data).unwrap();
+        assert!(collection.is_none());
+        assert!(find_allowed_collection(&CollectionRegistry::default(), metadata.data.creators.as_ref(), collection, |entry| entry.stakeable).is_err());
+    }
+}
@@ -1,16 +1,80 @@
 import * as anchor from "@project-serum/anchor";
 import { Program } from "@project-serum/anchor";
+import NodeWallet from "@project-serum/anchor/dist/cjs/nodewallet";
+import { Token, TOKEN_PROGRAM_ID } from "@solana/spl-token";
+import { BPF_LOADER_UPGRADEABLE_PROGRAM_ID, PublicKey } from "@solana/web3.js";
+import { assert } from "chai";
 import { Staking } from "../target/types/staking";
+import {
+  COLLECTION_REGISTRY_SEED,
+  DEFAULT_COLLECTIONS,
+  DEFAULT_REWARD_TIERS,
+  DEFAULT_WITHDRAW_DELAY,
+  GLOBAL_AUTHORITY_SEED,
+  REWARD_CONFIG_SEED,
+} from "../cli/types";
 
 describe("staking", () => {
   // Configure the client to use the local cluster.
-  anchor.setProvider(anchor.AnchorProvider.env());
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
 
   const program = anchor.workspace.Staking as Program<Staking>;
 
-  it("Is initialized!", async () => {
-    // Add your test here.
-    const tx = await program.methods.initialize().rpc();
+  it("Creates the original pool", async () => {
+    const rewardMint = await Token.createMint(
+      provider.connection,
+      (provider.wallet as NodeWallet).payer,
+      provider.wallet.publicKey,
+      null,
+      9,
+      TOKEN_PROGRAM_ID
+    );
+
+    // The original pool has no id seed
+    const [globalAuthority, bump] = await PublicKey.findProgramAddress(
+      [Buffer.from(GLOBAL_AUTHORITY_SEED)],
+      program.programId
+    );
+    const [rewardConfig] = await PublicKey.findProgramAddress(
+      [Buffer.from(REWARD_CONFIG_SEED), globalAuthority.toBuffer()],
+      program.programId
+    );
+    const [collectionRegistry] = await PublicKey.findProgramAddress(
+      [Buffer.from(COLLECTION_REGISTRY_SEED), globalAuthority.toBuffer()],
+      program.programId
+    );
+    // Only the upgrade authority the program was deployed with can create pools
+    const [programData] = await PublicKey.findProgramAddress(
+      [program.programId.toBuffer()],
+      BPF_LOADER_UPGRADEABLE_PROGRAM_ID
+    );
+
+    const tx = await program.methods
+      .createPool(
+        bump,
+        new anchor.BN(0),
+        new anchor.BN(DEFAULT_WITHDRAW_DELAY),
+        DEFAULT_COLLECTIONS,
+        DEFAULT_REWARD_TIERS
+      )
+      .accounts({
+        admin: provider.wallet.publicKey,
+        globalAuthority,
+        rewardConfig,
+        collectionRegistry,
+        rewardMint: rewardMint.publicKey,
+        program: program.programId,
+        programData,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      })
+      .rpc();
     console.log("Your transaction signature", tx);
+
+    const globalPool = await program.account.globalPool.fetch(globalAuthority);
+    assert.ok(globalPool.superAdmin.equals(provider.wallet.publicKey));
+    assert.ok(globalPool.rewardMint.equals(rewardMint.publicKey));
+    assert.equal(globalPool.withdrawDelay.toNumber(), DEFAULT_WITHDRAW_DELAY);
   });
 });